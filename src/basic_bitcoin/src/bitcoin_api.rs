@@ -1,55 +1,216 @@
-use candid::Principal;
-use ic_cdk::api::call::call_with_payment;
+use crate::network_profile;
+use bitcoin::{util::address::Payload, Address};
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::call::{call_with_payment, RejectionCode};
 use ic_cdk::api::management_canister::bitcoin::{
     BitcoinNetwork, GetBalanceRequest, GetCurrentFeePercentilesRequest, GetUtxosRequest,
-    GetUtxosResponse, MillisatoshiPerByte, Satoshi, SendTransactionRequest,
+    GetUtxosResponse, MillisatoshiPerByte, Satoshi, SendTransactionRequest, Utxo, UtxoFilter,
 };
+use std::collections::BTreeSet;
+use std::str::FromStr;
 
-// The fees for the various bitcoin endpoints.
-const GET_BALANCE_COST_CYCLES: u64 = 100_000_000;
-const GET_UTXOS_COST_CYCLES: u64 = 10_000_000_000;
-const GET_CURRENT_FEE_PERCENTILES_CYCLES: u64 = 100_000_000;
-const SEND_TRANSACTION_BASE_CYCLES: u64 = 5_000_000_000;
-const SEND_TRANSACTION_PER_BYTE_CYCLES: u64 = 20_000_000;
+/// The maximum number of pages of UTXOs fetched per address, unless
+/// controllers set another (see `WalletState::max_utxo_pages`).
+pub const DEFAULT_MAX_UTXO_PAGES: usize = 20;
 
-/// Returns the balance of the given bitcoin address.
+/// The reasons a call to the bitcoin API can fail.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum BitcoinApiError {
+    /// The management canister rejected the call.
+    Rejected { code: i32, message: String },
+    /// The canister does not hold enough cycles to pay for the call.
+    InsufficientCycles { available: u128, required: u64 },
+    /// The address belongs to a different bitcoin network than the one
+    /// the canister is connected to.
+    NetworkMismatch {
+        expected: BitcoinNetwork,
+        address: String,
+    },
+    /// The address could not be parsed.
+    MalformedAddress(String),
+}
+
+impl From<(RejectionCode, String)> for BitcoinApiError {
+    fn from((code, message): (RejectionCode, String)) -> Self {
+        Self::Rejected {
+            code: code as i32,
+            message,
+        }
+    }
+}
+
+/// Checks that the canister can pay for a call that costs `required` cycles.
+fn ensure_cycles(required: u64) -> Result<(), BitcoinApiError> {
+    let available = ic_cdk::api::canister_balance128();
+    if available < required as u128 {
+        return Err(BitcoinApiError::InsufficientCycles {
+            available,
+            required,
+        });
+    }
+    Ok(())
+}
+
+/// Checks that the given address is well-formed and uses the prefixes of
+/// `network`.
+///
+/// Legacy testnet and regtest addresses share the same prefixes, so a
+/// regtest canister accepts both.
+pub fn check_address(network: BitcoinNetwork, address: &str) -> Result<(), BitcoinApiError> {
+    let parsed = Address::from_str(address)
+        .map_err(|err| BitcoinApiError::MalformedAddress(format!("{}: {}", address, err)))?;
+
+    let profile = network_profile::profile(network);
+    let matches = match parsed.payload {
+        Payload::PubkeyHash(_) => base58_version(address) == Some(profile.p2pkh_prefix),
+        Payload::ScriptHash(_) => base58_version(address) == Some(profile.p2sh_prefix),
+        Payload::WitnessProgram { .. } => address
+            .to_lowercase()
+            .starts_with(&format!("{}1", profile.bech32_hrp)),
+    };
+
+    if !matches {
+        return Err(BitcoinApiError::NetworkMismatch {
+            expected: network,
+            address: address.to_string(),
+        });
+    }
+    Ok(())
+}
+
+// Returns the version byte of a base58 address.
+fn base58_version(address: &str) -> Option<u8> {
+    bs58::decode(address).into_vec().ok()?.first().copied()
+}
+
+/// Returns the balance of the given bitcoin address, counting only UTXOs
+/// with at least `min_confirmations` confirmations if set.
 ///
 /// Relies on the `bitcoin_get_balance` endpoint.
 /// See https://internetcomputer.org/docs/current/references/ic-interface-spec/#ic-bitcoin_get_balance
-pub async fn get_balance(network: BitcoinNetwork, address: String) -> u64 {
-    let balance_res: Result<(Satoshi,), _> = call_with_payment(
+pub async fn get_balance(
+    network: BitcoinNetwork,
+    address: String,
+    min_confirmations: Option<u32>,
+) -> Result<u64, BitcoinApiError> {
+    check_address(network, &address)?;
+    let cost = network_profile::profile(network).cycle_costs.get_balance;
+    ensure_cycles(cost)?;
+
+    let (balance,): (Satoshi,) = call_with_payment(
         Principal::management_canister(),
         "bitcoin_get_balance",
         (GetBalanceRequest {
             address,
             network: network.into(),
-            min_confirmations: None,
+            min_confirmations,
         },),
-        GET_BALANCE_COST_CYCLES,
+        cost,
     )
-    .await;
+    .await?;
 
-    balance_res.unwrap().0
+    Ok(balance)
 }
 
-/// Returns the UTXOs of the given bitcoin address.
+/// Returns the UTXOs of the given bitcoin address, optionally restricted by
+/// a minimum number of confirmations or continued from a previous page.
 ///
 /// NOTE: Relies on the `bitcoin_get_utxos` endpoint.
 /// See https://internetcomputer.org/docs/current/references/ic-interface-spec/#ic-bitcoin_get_utxos
-pub async fn get_utxos(network: BitcoinNetwork, address: String) -> GetUtxosResponse {
-    let utxos_res: Result<(GetUtxosResponse,), _> = call_with_payment(
+pub async fn get_utxos(
+    network: BitcoinNetwork,
+    address: String,
+    filter: Option<UtxoFilter>,
+) -> Result<GetUtxosResponse, BitcoinApiError> {
+    get_utxos_with_filter(network, address, filter).await
+}
+
+/// Returns a single page of UTXOs of the given bitcoin address.
+///
+/// `page` is the `next_page` value of a previous response, or `None` for the
+/// first page.
+pub async fn get_utxos_page(
+    network: BitcoinNetwork,
+    address: String,
+    page: Option<Vec<u8>>,
+) -> Result<GetUtxosResponse, BitcoinApiError> {
+    get_utxos_with_filter(network, address, page.map(UtxoFilter::Page)).await
+}
+
+/// The UTXOs of an address gathered over several pages.
+#[derive(Clone, Debug, Default)]
+pub struct UtxoListing {
+    pub utxos: Vec<Utxo>,
+    /// Whether `max_pages` ran out before the last page, in which case some
+    /// UTXOs of the address are missing from `utxos`.
+    pub truncated: bool,
+    /// The height of the chain tip the UTXOs were listed at.
+    pub tip_height: u32,
+}
+
+/// Returns all UTXOs of the given bitcoin address, following `next_page`
+/// until the last page or until `max_pages` pages have been fetched.
+///
+/// If `min_confirmations` is set, only UTXOs with at least that many
+/// confirmations are returned. UTXOs are returned in the order the pages
+/// list them, without duplicates.
+pub async fn get_all_utxos(
+    network: BitcoinNetwork,
+    address: String,
+    min_confirmations: Option<u32>,
+    max_pages: usize,
+) -> Result<UtxoListing, BitcoinApiError> {
+    let mut seen = BTreeSet::new();
+    let mut listing = UtxoListing::default();
+    // Page tokens carry the confirmations filter of the first request, so
+    // later pages need no filtering here.
+    let mut filter = min_confirmations.map(UtxoFilter::MinConfirmations);
+
+    for _ in 0..max_pages {
+        let response = get_utxos_with_filter(network, address.clone(), filter).await?;
+        listing.tip_height = response.tip_height;
+        for utxo in response.utxos {
+            if seen.insert((utxo.outpoint.txid.clone(), utxo.outpoint.vout)) {
+                listing.utxos.push(utxo);
+            }
+        }
+
+        match response.next_page {
+            Some(page) => filter = Some(UtxoFilter::Page(page)),
+            None => return Ok(listing),
+        }
+    }
+
+    ic_cdk::print(format!(
+        "Stopped fetching UTXOs of {} after {} pages.",
+        address, max_pages
+    ));
+    listing.truncated = true;
+    Ok(listing)
+}
+
+async fn get_utxos_with_filter(
+    network: BitcoinNetwork,
+    address: String,
+    filter: Option<UtxoFilter>,
+) -> Result<GetUtxosResponse, BitcoinApiError> {
+    check_address(network, &address)?;
+    let cost = network_profile::profile(network).cycle_costs.get_utxos;
+    ensure_cycles(cost)?;
+
+    let (utxos,): (GetUtxosResponse,) = call_with_payment(
         Principal::management_canister(),
         "bitcoin_get_utxos",
         (GetUtxosRequest {
             address,
             network: network.into(),
-            filter: None,
+            filter,
         },),
-        GET_UTXOS_COST_CYCLES,
+        cost,
     )
-    .await;
+    .await?;
 
-    utxos_res.unwrap().0
+    Ok(utxos)
 }
 
 /// Returns the 100 fee percentiles measured in millisatoshi/byte.
@@ -57,29 +218,41 @@ pub async fn get_utxos(network: BitcoinNetwork, address: String) -> GetUtxosResp
 ///
 /// Relies on the `bitcoin_get_current_fee_percentiles` endpoint.
 /// See https://internetcomputer.org/docs/current/references/ic-interface-spec/#ic-bitcoin_get_current_fee_percentiles
-pub async fn get_current_fee_percentiles(network: BitcoinNetwork) -> Vec<MillisatoshiPerByte> {
-    let res: Result<(Vec<MillisatoshiPerByte>,), _> = call_with_payment(
+pub async fn get_current_fee_percentiles(
+    network: BitcoinNetwork,
+) -> Result<Vec<MillisatoshiPerByte>, BitcoinApiError> {
+    let cost = network_profile::profile(network)
+        .cycle_costs
+        .get_current_fee_percentiles;
+    ensure_cycles(cost)?;
+
+    let (percentiles,): (Vec<MillisatoshiPerByte>,) = call_with_payment(
         Principal::management_canister(),
         "bitcoin_get_current_fee_percentiles",
         (GetCurrentFeePercentilesRequest {
             network: network.into(),
         },),
-        GET_CURRENT_FEE_PERCENTILES_CYCLES,
+        cost,
     )
-    .await;
+    .await?;
 
-    res.unwrap().0
+    Ok(percentiles)
 }
 
 /// Sends a (signed) transaction to the bitcoin network.
 ///
 /// Relies on the `bitcoin_send_transaction` endpoint.
 /// See https://internetcomputer.org/docs/current/references/ic-interface-spec/#ic-bitcoin_send_transaction
-pub async fn send_transaction(network: BitcoinNetwork, transaction: Vec<u8>) {
-    let transaction_fee = SEND_TRANSACTION_BASE_CYCLES
-        + (transaction.len() as u64) * SEND_TRANSACTION_PER_BYTE_CYCLES;
+pub async fn send_transaction(
+    network: BitcoinNetwork,
+    transaction: Vec<u8>,
+) -> Result<(), BitcoinApiError> {
+    let costs = network_profile::profile(network).cycle_costs;
+    let transaction_fee = costs.send_transaction_base
+        + (transaction.len() as u64) * costs.send_transaction_per_byte;
+    ensure_cycles(transaction_fee)?;
 
-    let res: Result<(), _> = call_with_payment(
+    let () = call_with_payment(
         Principal::management_canister(),
         "bitcoin_send_transaction",
         (SendTransactionRequest {
@@ -88,7 +261,7 @@ pub async fn send_transaction(network: BitcoinNetwork, transaction: Vec<u8>) {
         },),
         transaction_fee,
     )
-    .await;
+    .await?;
 
-    res.unwrap();
+    Ok(())
 }
@@ -4,294 +4,1862 @@
 //! and how bitcoin transactions can be signed. It is missing several
 //! pieces that any production-grade wallet would have, including:
 //!
-//! * Support for address types that aren't P2PKH.
-//! * Caching spent UTXOs so that they are not reused in future transactions.
-//! * Option to set the fee.
-use crate::types::SendRequest;
-use crate::{bitcoin_api, ecdsa_api, DERIVATION_PATH};
+//! * Support for address types other than P2PKH, P2WPKH and key-path P2TR.
+use crate::bitcoin_api::{self, BitcoinApiError};
+use crate::ecdsa_api;
+use crate::key_cache;
+use crate::network_profile;
+use crate::schnorr_api;
+use crate::types::{
+    AccelerateDepositArgs, BatchOptions, BatchTransferResult, CoinSelection, ConsolidationResult,
+    Payout, PayoutResult, RebroadcastConfig, SendRequest, TransactionRecord, TransactionRequest,
+    TransferEstimate, TransferOutput, TransferResult,
+};
+use crate::wallet_state::{
+    mutate_wallet_state, outpoint_key, read_wallet_state, KeyAlgorithm, OutpointKey,
+    MAX_MIN_CONFIRMATIONS,
+};
 use bitcoin::util::psbt::serialize::Serialize;
+use candid::{CandidType, Deserialize};
 use bitcoin::{
+    bech32::{self, ToBase32},
     blockdata::{script::Builder, witness::Witness},
     hashes::Hash,
-    Address, AddressType, EcdsaSighashType, OutPoint, Script, Transaction, TxIn, TxOut, Txid,
+    secp256k1::{Secp256k1, XOnlyPublicKey},
+    util::sighash::{Prevouts, SighashCache},
+    Address, AddressType, EcdsaSighashType, OutPoint, SchnorrSighashType, Script, Transaction,
+    TxIn, TxOut, Txid,
 };
+use ic_cdk::api::call::{CallResult, RejectionCode};
 use ic_cdk::api::management_canister::bitcoin::{MillisatoshiPerByte, BitcoinNetwork, Satoshi, Utxo};
 use ic_cdk::print;
 use ic_ckbtc_minter_syron::address::BitcoinAddress;
 use ic_ckbtc_minter_syron::{
-    state::Network,
+    address,
+    state::{read_state, Network},
+    updates::{
+        self,
+        get_btc_address::GetBtcAddressArgs,
+        get_withdrawal_account::compute_subaccount,
+    },
     tx::{self, SignedTransaction, UnsignedInput, UnsignedTransaction, SignedInput},
     management::{sign_with_ecdsa, CallError},
     signature::EncodedSignature
 };
 use sha2::Digest;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
 use std::str::FromStr;
 use serde_bytes::ByteBuf;
 use ic_ic00_types::DerivationPath;
+use icrc_ledger_types::icrc1::account::Account;
 
 const SIG_HASH_TYPE: EcdsaSighashType = EcdsaSighashType::All;
 
-/// Returns the P2PKH address of this canister at the given derivation path.
-pub async fn get_p2pkh_address(
+// Input sequence number that signals replaceability (BIP-125).
+const RBF_SEQUENCE: u32 = 0xfffffffd;
+
+// The minimum fee rate increase relays require from a replacement, in
+// millisatoshi/vbyte.
+const INCREMENTAL_RELAY_FEE_PER_BYTE: MillisatoshiPerByte = 1000;
+
+// The lowest fee rate relays accept, in millisatoshi/vbyte.
+const MIN_RELAY_FEE_PER_BYTE: MillisatoshiPerByte = 1000;
+
+// The fee percentile used when the caller does not choose one.
+const DEFAULT_FEE_PERCENTILE: u8 = 50;
+
+// The virtual sizes of the outputs and of the inputs spending them, per
+// address type of the wallet.
+const P2PKH_OUTPUT_VSIZE: u64 = 34;
+const P2PKH_INPUT_VSIZE: u64 = 148;
+const P2WPKH_OUTPUT_VSIZE: u64 = 31;
+const P2WPKH_INPUT_VSIZE: u64 = 68;
+const P2TR_OUTPUT_VSIZE: u64 = 43;
+const P2TR_INPUT_VSIZE: u64 = 58;
+
+// The most UTXOs a consolidation spends. Each input takes a signing call of
+// about 26B cycles, and the 100k vbytes of a standard transaction fit about
+// 1,400 P2WPKH inputs.
+const MAX_CONSOLIDATION_INPUTS: usize = 500;
+
+// A child accelerating a deposit spends at most the deposit value divided
+// by this on fees, i.e. 5%.
+const ACCELERATION_MAX_FEE_DIVISOR: u64 = 20;
+
+// The virtual sizes a standard transaction can have: relays reject smaller
+// ones (`MIN_STANDARD_TX_NONWITNESS_SIZE`) and larger ones alike.
+const MIN_STANDARD_TX_VSIZE: u64 = 65;
+const MAX_STANDARD_TX_VSIZE: u64 = 100_000;
+
+/// The reasons sending bitcoin from the wallet can fail.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum WalletError {
+    /// A call to the bitcoin API failed.
+    BitcoinApi(BitcoinApiError),
+    /// The amount sent to the destination is too small to be relayed.
+    AmountBelowDust { amount: u64, dust_threshold: u64 },
+    /// The available UTXOs do not cover the amount and the fee.
+    InsufficientFunds { available: u64, required: u64 },
+    /// The requested fee percentile is not between 0 and 99.
+    InvalidFeePercentile(u8),
+    /// The fee of the transaction exceeds the maximum set by the caller.
+    FeeAboveMax { fee: u64, max_fee: u64 },
+    /// The fee rate exceeds the maximum set by the caller, or the highest
+    /// current fee percentile.
+    FeeRateAboveMax { fee_rate: u64, max_fee_rate: u64 },
+    /// There are fewer than two UTXOs available to merge.
+    NothingToConsolidate,
+    /// A batch transfer without any payouts.
+    EmptyBatch,
+    /// The wallet has no record of the transaction.
+    UnknownTransaction(String),
+    /// The transaction was already replaced by a fee bump.
+    AlreadyReplaced { replacement: String },
+    /// The transaction is already in the block at `height`.
+    AlreadyConfirmed { height: u32 },
+    /// Another call is replacing the transaction.
+    BumpInProgress(String),
+    /// The fee rate is below what relays accept, or a replacement does not
+    /// pay a higher fee rate than the original.
+    FeeRateTooLow { fee_rate: u64, min_fee_rate: u64 },
+    /// The deposit is already in a block, so a child cannot speed it up.
+    DepositConfirmed,
+    /// A child transaction already spends the deposit.
+    DepositAlreadyAccelerated { txid: String },
+    /// The management canister failed to sign the transaction.
+    Signing(String),
+    /// `update_balance` minted nothing, for the given reason.
+    NothingMinted(String),
+    /// The caller must wait until `retry_at`, in nanoseconds since the epoch.
+    RateLimited { retry_at: u64 },
+    /// The parent of a deposit to accelerate cannot have the given size
+    /// and fee.
+    InvalidParent { parent_vsize: u64, parent_fee: u64 },
+    /// The request requires more confirmations than the wallet supports.
+    MinConfirmationsAboveMax {
+        min_confirmations: u32,
+        max_min_confirmations: u32,
+    },
+}
+
+/// How the fee of an outgoing transaction is chosen.
+#[derive(Clone, Debug, Default)]
+pub struct FeeOptions {
+    /// An explicit fee rate in millisatoshi/vbyte, at least the 1_000 relays
+    /// accept. Takes precedence over `fee_percentile`.
+    pub fee_rate: Option<MillisatoshiPerByte>,
+    /// The percentile of recent fee rates to pay, 50 if not set.
+    pub fee_percentile: Option<u8>,
+    /// The maximum total fee in satoshi. The transaction is not sent if
+    /// its fee is higher.
+    pub max_fee: Option<Satoshi>,
+}
+
+impl From<BitcoinApiError> for WalletError {
+    fn from(err: BitcoinApiError) -> Self {
+        Self::BitcoinApi(err)
+    }
+}
+
+impl From<CallError> for WalletError {
+    fn from(err: CallError) -> Self {
+        Self::Signing(err.to_string())
+    }
+}
+
+impl From<(RejectionCode, String)> for WalletError {
+    fn from((code, message): (RejectionCode, String)) -> Self {
+        Self::Signing(format!("{:?}: {}", code, message))
+    }
+}
+
+/// Returns the P2PKH address of this canister at the given derivation path,
+/// or `None` if the root key of `key_name` is not cached yet.
+pub fn get_p2pkh_address(
+    network: BitcoinNetwork,
+    key_name: &str,
+    derivation_path: &[Vec<u8>],
+) -> Option<String> {
+    // Derive the public key of the given derivation path.
+    let public_key =
+        key_cache::cached_public_key(KeyAlgorithm::EcdsaSecp256k1, key_name, derivation_path)?;
+
+    // Compute the address.
+    Some(public_key_to_p2pkh_address(network, &public_key))
+}
+
+/// Returns the P2WPKH address of this canister at the given derivation path,
+/// or `None` if the root key of `key_name` is not cached yet.
+pub fn get_p2wpkh_address(
     network: BitcoinNetwork,
+    key_name: &str,
+    derivation_path: &[Vec<u8>],
+) -> Option<String> {
+    // Derive the public key of the given derivation path.
+    let public_key =
+        key_cache::cached_public_key(KeyAlgorithm::EcdsaSecp256k1, key_name, derivation_path)?;
+
+    Some(public_key_to_p2wpkh_address(network, &public_key))
+}
+
+/// Sends a transaction to the network that transfers the given amount to the
+/// given destination, where the source of the funds is the canister itself
+/// at the given derivation path.
+pub async fn send(
+    network: BitcoinNetwork,
+    derivation_path: Vec<Vec<u8>>,
     key_name: String,
+    request: &SendRequest,
+) -> Result<Txid, WalletError> {
+    // Fetch our public key and P2PKH address.
+    let public_key = key_cache::ecdsa_public_key(key_name.clone(), derivation_path.clone()).await;
+    let own_address = public_key_to_p2pkh_address(network, &public_key);
+    send_from_address(
+        network,
+        derivation_path,
+        key_name,
+        request,
+        Address::from_str(&own_address).unwrap(),
+        SpendingKey::P2pkh { public_key },
+    )
+    .await
+}
+
+/// Returns the P2TR address of this canister at the given derivation path,
+/// or `None` if the root key of `key_name` is not cached yet.
+/// The address commits to no script, so it is only spendable through the
+/// key path.
+pub fn get_p2tr_address(
+    network: BitcoinNetwork,
+    key_name: &str,
+    derivation_path: &[Vec<u8>],
+) -> Option<String> {
+    // Derive the public key of the given derivation path.
+    let public_key = key_cache::cached_public_key(
+        KeyAlgorithm::SchnorrBip340Secp256k1,
+        key_name,
+        derivation_path,
+    )?;
+
+    Some(public_key_to_p2tr_address(network, &public_key).to_string())
+}
+
+/// Sends a transaction to the network that transfers the given amount to the
+/// given destination, where the source of the funds is the canister's P2TR
+/// address at the given derivation path.
+pub async fn send_p2tr(
+    network: BitcoinNetwork,
     derivation_path: Vec<Vec<u8>>,
-) -> String {
-    // Fetch the public key of the given derivation path.
-    let public_key = ecdsa_api::ecdsa_public_key(key_name, derivation_path).await;
+    key_name: String,
+    request: &SendRequest,
+) -> Result<Txid, WalletError> {
+    // Fetch our public key and P2TR address.
+    let public_key = key_cache::schnorr_public_key(key_name.clone(), derivation_path.clone()).await;
+    send_from_address(
+        network,
+        derivation_path,
+        key_name,
+        request,
+        public_key_to_p2tr_address(network, &public_key),
+        SpendingKey::P2tr,
+    )
+    .await
+}
 
-    // Compute the address.
-    public_key_to_p2pkh_address(network, &public_key)
+// The key spending the UTXOs of the address `send_from_address` sends from.
+enum SpendingKey {
+    // The ECDSA key of a P2PKH address.
+    P2pkh { public_key: Vec<u8> },
+    // The Schnorr key of a P2TR address, which signs through the key path.
+    P2tr,
+}
+
+// Sends the transfer in `request` from `own_address`, whose UTXOs `key`
+// spends, and records it.
+async fn send_from_address(
+    network: BitcoinNetwork,
+    derivation_path: Vec<Vec<u8>>,
+    key_name: String,
+    request: &SendRequest,
+    own_address: Address,
+    key: SpendingKey,
+) -> Result<Txid, WalletError> {
+    let amount = request.amount_in_satoshi;
+    let min_confirmations = min_confirmations(network, request.min_confirmations)?;
+    let fee_options = &request.fee_options();
+    let fee_per_byte = fee_per_byte(network, fee_options).await?;
+    let own_address_str = own_address.to_string();
+
+    print("Fetching UTXOs...");
+    let listing = bitcoin_api::get_all_utxos(
+        network,
+        own_address_str.clone(),
+        min_confirmations,
+        read_wallet_state(|s| s.max_utxo_pages()),
+    )
+    .await?;
+
+    release_utxo_locks(&listing);
+    let own_utxos = listing.utxos;
+
+    bitcoin_api::check_address(network, &request.destination_address)?;
+    let dst_address = Address::from_str(&request.destination_address).unwrap();
+    check_dust(amount, dust_threshold(&dst_address))?;
+    let (change_output_vsize, change_input_vsize, transaction_request) = match &key {
+        SpendingKey::P2pkh { .. } => (
+            P2PKH_OUTPUT_VSIZE,
+            P2PKH_INPUT_VSIZE,
+            TransactionRequest::Send(request.clone()),
+        ),
+        SpendingKey::P2tr => (
+            P2TR_OUTPUT_VSIZE,
+            P2TR_INPUT_VSIZE,
+            TransactionRequest::TransferP2tr(request.clone()),
+        ),
+    };
+    let selector = coin_selector(
+        request.coin_selection.unwrap_or_default(),
+        cost_of_change(change_output_vsize, change_input_vsize, fee_per_byte),
+    );
+
+    loop {
+        let available_utxos = read_wallet_state(|s| s.available_utxos(&own_utxos));
+
+        // Build the transaction that sends `amount` to the destination address.
+        let (transaction, fee) = build_transaction(
+            &key,
+            &own_address,
+            &available_utxos,
+            &dst_address,
+            amount,
+            fee_per_byte,
+            selector.as_ref(),
+        )
+        .await?;
+        check_max_fee(fee, fee_options)?;
+
+        let tx_bytes = transaction.serialize();
+        print(&format!("Transaction to sign: {}", hex::encode(tx_bytes)));
+
+        // Sign the transaction.
+        let signed_transaction = match &key {
+            SpendingKey::P2pkh { public_key } => {
+                sign_transaction_p2pkh(
+                    public_key,
+                    &own_address,
+                    transaction,
+                    key_name.clone(),
+                    derivation_path.clone(),
+                    ecdsa_api::sign_with_ecdsa,
+                )
+                .await?
+            }
+            SpendingKey::P2tr => {
+                sign_transaction_p2tr(
+                    &own_address,
+                    &available_utxos,
+                    transaction,
+                    key_name.clone(),
+                    derivation_path.clone(),
+                    schnorr_api::sign_with_schnorr,
+                )
+                .await?
+            }
+        };
+
+        // Another call may have spent the same UTXOs while we were signing.
+        let txid = signed_transaction.txid();
+        let spent: Vec<OutpointKey> = signed_transaction
+            .input
+            .iter()
+            .map(|input| {
+                (
+                    input.previous_output.txid.into_inner().to_vec(),
+                    input.previous_output.vout,
+                )
+            })
+            .collect();
+        if !mutate_wallet_state(|s| {
+            s.try_lock(&spent, &own_address_str, &txid.to_string(), ic_cdk::api::time())
+        }) {
+            print("UTXOs were spent concurrently, rebuilding transaction...");
+            continue;
+        }
+
+        let signed_transaction_bytes = signed_transaction.serialize();
+        print(&format!(
+            "Signed transaction: {}",
+            hex::encode(&signed_transaction_bytes)
+        ));
+
+        print("Sending transaction...");
+        if let Err(err) =
+            bitcoin_api::send_transaction(network, signed_transaction_bytes.clone()).await
+        {
+            mutate_wallet_state(|s| s.unlock(&spent));
+            return Err(err.into());
+        }
+        print("Done");
+
+        let outputs: Vec<TransferOutput> = signed_transaction
+            .output
+            .iter()
+            .map(|output| TransferOutput {
+                address: Address::from_script(
+                    &output.script_pubkey,
+                    network_profile::current().bitcoin_network(),
+                )
+                .map(|address| address.to_string())
+                .unwrap_or_default(),
+                value: output.value,
+            })
+            .collect();
+        record_transaction(TransactionRecord {
+            txid: txid.to_string(),
+            request: transaction_request.clone(),
+            caller: ic_cdk::caller(),
+            timestamp: ic_cdk::api::time(),
+            source_address: own_address_str.clone(),
+            derivation_path: derivation_path.clone(),
+            inputs: spent_utxos(&available_utxos, &spent),
+            change_vout: (outputs.len() > 1).then(|| 1),
+            outputs,
+            fee,
+            fee_rate: fee_per_byte,
+            raw_transaction: signed_transaction_bytes,
+            last_broadcast: ic_cdk::api::time(),
+            rebroadcasts: 0,
+            stuck: false,
+            confirmed_height: None,
+            replaced_by: None,
+        });
+
+        return Ok(txid);
+    }
+}
+
+pub async fn send_p2wpkh(
+    btc_network: BitcoinNetwork,
+    derivation_path: Vec<Vec<u8>>,
+    key_name: String,
+    request: &SendRequest,
+) -> Result<TransferResult, WalletError> {
+    let source = load_p2wpkh_source(
+        btc_network,
+        key_name.clone(),
+        derivation_path.clone(),
+        min_confirmations(btc_network, request.min_confirmations)?,
+    )
+    .await?;
+    let destinations = vec![parse_p2wpkh_destination(
+        &source,
+        &request.destination_address,
+        request.amount_in_satoshi,
+    )?];
+
+    let sent = send_p2wpkh_outputs(
+        btc_network,
+        derivation_path,
+        key_name,
+        &source,
+        &destinations,
+        &request.fee_options(),
+        request.coin_selection.unwrap_or_default(),
+        TransactionRequest::Transfer(request.clone()),
+    )
+    .await?;
+
+    // Both ids are shown in the byte order block explorers use.
+    let mut wtxid = sent.signed_transaction.wtxid();
+    wtxid.reverse();
+
+    Ok(TransferResult {
+        txid: sent.txid.to_string(),
+        wtxid: hex::encode(wtxid),
+        fee: sent.fee,
+        vsize: sent.signed_transaction.vsize() as u64,
+        inputs_used: sent.inputs,
+        // The destination output comes first, the change output (if any) last.
+        change: sent
+            .signed_transaction
+            .outputs
+            .iter()
+            .skip(destinations.len())
+            .map(|output| output.value)
+            .sum(),
+    })
+}
+
+/// Pays all the given outputs in a single transaction with one change output.
+pub async fn send_p2wpkh_batch(
+    btc_network: BitcoinNetwork,
+    derivation_path: Vec<Vec<u8>>,
+    key_name: String,
+    payouts: &[Payout],
+    options: &BatchOptions,
+) -> Result<BatchTransferResult, WalletError> {
+    if payouts.is_empty() {
+        return Err(WalletError::EmptyBatch);
+    }
+
+    let source = load_p2wpkh_source(
+        btc_network,
+        key_name.clone(),
+        derivation_path.clone(),
+        min_confirmations(btc_network, options.min_confirmations)?,
+    )
+    .await?;
+    let destinations = payouts
+        .iter()
+        .map(|payout| {
+            parse_p2wpkh_destination(
+                &source,
+                &payout.destination_address,
+                payout.amount_in_satoshi,
+            )
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let sent = send_p2wpkh_outputs(
+        btc_network,
+        derivation_path,
+        key_name,
+        &source,
+        &destinations,
+        &options.fee_options(),
+        options.coin_selection.unwrap_or_default(),
+        TransactionRequest::TransferBatch(payouts.to_vec()),
+    )
+    .await?;
+
+    Ok(BatchTransferResult {
+        txid: sent.txid.to_string(),
+        fee: sent.fee,
+        // Destination outputs keep the order of the payouts.
+        outputs: payouts
+            .iter()
+            .enumerate()
+            .map(|(vout, payout)| PayoutResult {
+                destination_address: payout.destination_address.clone(),
+                amount_in_satoshi: payout.amount_in_satoshi,
+                vout: vout as u32,
+            })
+            .collect(),
+    })
+}
+
+// A transaction signed and sent by the wallet.
+struct SentTransaction {
+    txid: ic_ckbtc_minter_syron::tx::Txid,
+    signed_transaction: SignedTransaction,
+    inputs: Vec<Utxo>,
+    fee: Satoshi,
+}
+
+// Builds, signs and sends a transaction paying `destinations` from `source`.
+async fn send_p2wpkh_outputs(
+    btc_network: BitcoinNetwork,
+    derivation_path: Vec<Vec<u8>>,
+    key_name: String,
+    source: &P2wpkhSource,
+    destinations: &[(BitcoinAddress, Satoshi)],
+    fee_options: &FeeOptions,
+    coin_selection: CoinSelection,
+    request: TransactionRequest,
+) -> Result<SentTransaction, WalletError> {
+    let fee_per_byte = fee_per_byte(btc_network, fee_options).await?;
+    let selector = coin_selector(
+        coin_selection,
+        cost_of_change(P2WPKH_OUTPUT_VSIZE, P2WPKH_INPUT_VSIZE, fee_per_byte),
+    );
+
+    loop {
+        let available_utxos = read_wallet_state(|s| s.available_utxos(&source.utxos));
+
+        // Build the transaction that pays the destination addresses.
+        let (transaction, fee) = build_unsigned_transaction(
+            &source.public_key,
+            source.address.clone(),
+            &available_utxos,
+            destinations,
+            fee_per_byte,
+            selector.as_ref(),
+        )
+        .await?;
+        check_max_fee(fee, fee_options)?;
+
+        let txid = transaction.txid();
+        let spent: Vec<OutpointKey> = transaction
+            .inputs
+            .iter()
+            .map(|input| {
+                (
+                    input.previous_output.txid.as_ref().to_vec(),
+                    input.previous_output.vout,
+                )
+            })
+            .collect();
+
+        // Sign the transaction.
+        let signed_transaction: SignedTransaction = sign_transaction_p2wpkh(
+            &source.public_key,
+            transaction,
+            key_name.clone(),
+            derivation_path.clone(),
+            sign_with_ecdsa,
+        )
+        .await?;
+
+        // Another call may have spent the same UTXOs while we were signing.
+        if !mutate_wallet_state(|s| {
+            s.try_lock(&spent, &source.address_str, &txid.to_string(), ic_cdk::api::time())
+        }) {
+            print("UTXOs were spent concurrently, rebuilding transaction...");
+            continue;
+        }
+
+        print("Sending transaction...");
+        let signed_transaction_bytes = signed_transaction.serialize();
+        if let Err(err) =
+            bitcoin_api::send_transaction(btc_network, signed_transaction_bytes.clone()).await
+        {
+            mutate_wallet_state(|s| s.unlock(&spent));
+            return Err(err.into());
+        }
+        print("Done");
+
+        let inputs = spent_utxos(&available_utxos, &spent);
+        record_transaction(TransactionRecord {
+            txid: txid.to_string(),
+            request,
+            caller: ic_cdk::caller(),
+            timestamp: ic_cdk::api::time(),
+            source_address: source.address_str.clone(),
+            derivation_path: derivation_path.clone(),
+            inputs: inputs.clone(),
+            outputs: signed_transaction
+                .outputs
+                .iter()
+                .map(|output| TransferOutput {
+                    address: output.address.display(source.network),
+                    value: output.value,
+                })
+                .collect(),
+            // The change output, if any, follows the destination outputs.
+            change_vout: (signed_transaction.outputs.len() > destinations.len())
+                .then(|| destinations.len() as u32),
+            fee,
+            fee_rate: fee_per_byte,
+            raw_transaction: signed_transaction_bytes,
+            last_broadcast: ic_cdk::api::time(),
+            rebroadcasts: 0,
+            stuck: false,
+            confirmed_height: None,
+            replaced_by: None,
+        });
+
+        return Ok(SentTransaction {
+            txid,
+            signed_transaction,
+            inputs,
+            fee,
+        });
+    }
+}
+
+/// Builds the transaction that `send_p2wpkh` would send for the same
+/// arguments and describes it, without signing or sending it.
+pub async fn estimate_p2wpkh(
+    btc_network: BitcoinNetwork,
+    derivation_path: Vec<Vec<u8>>,
+    key_name: String,
+    request: &SendRequest,
+) -> Result<TransferEstimate, WalletError> {
+    let fee_options = &request.fee_options();
+    let fee_per_byte = fee_per_byte(btc_network, fee_options).await?;
+
+    let source = load_p2wpkh_source(
+        btc_network,
+        key_name,
+        derivation_path,
+        min_confirmations(btc_network, request.min_confirmations)?,
+    )
+    .await?;
+    let destinations = vec![parse_p2wpkh_destination(
+        &source,
+        &request.destination_address,
+        request.amount_in_satoshi,
+    )?];
+    let selector = coin_selector(
+        request.coin_selection.unwrap_or_default(),
+        cost_of_change(P2WPKH_OUTPUT_VSIZE, P2WPKH_INPUT_VSIZE, fee_per_byte),
+    );
+
+    let available_utxos = read_wallet_state(|s| s.available_utxos(&source.utxos));
+    let (transaction, fee) = build_unsigned_transaction(
+        &source.public_key,
+        source.address.clone(),
+        &available_utxos,
+        &destinations,
+        fee_per_byte,
+        selector.as_ref(),
+    )
+    .await?;
+    check_max_fee(fee, fee_options)?;
+
+    // The size only depends on the shape of the signatures, so the mock
+    // signer gives the size of the real transaction.
+    let signed_transaction = sign_transaction_p2wpkh(
+        &source.public_key,
+        transaction.clone(),
+        String::from(""), // mock key name
+        vec![],           // mock derivation path
+        mock_signer_p2wpkh,
+    )
+    .await?;
+
+    let inputs = transaction
+        .inputs
+        .iter()
+        .filter_map(|input| {
+            available_utxos.iter().find(|utxo| {
+                utxo.outpoint.txid == input.previous_output.txid.as_ref()
+                    && utxo.outpoint.vout == input.previous_output.vout
+            })
+        })
+        .cloned()
+        .collect();
+
+    Ok(TransferEstimate {
+        inputs,
+        outputs: transaction
+            .outputs
+            .iter()
+            .map(|output| TransferOutput {
+                address: output.address.display(source.network),
+                value: output.value,
+            })
+            .collect(),
+        vsize: signed_transaction.vsize() as u64,
+        fee,
+        // The destination outputs come first, the change output (if any) last.
+        change: transaction
+            .outputs
+            .iter()
+            .skip(destinations.len())
+            .map(|output| output.value)
+            .sum(),
+    })
+}
+
+/// Merges up to `max_inputs` of the smallest UTXOs of the canister's P2WPKH
+/// address into a single output paying back to the same address. At most
+/// `MAX_CONSOLIDATION_INPUTS` are merged, and UTXOs worth less than the fee
+/// of spending them are left alone.
+///
+/// Nothing is sent if the current median fee rate is above `max_fee_rate`.
+pub async fn consolidate_p2wpkh(
+    btc_network: BitcoinNetwork,
+    derivation_path: Vec<Vec<u8>>,
+    key_name: String,
+    max_inputs: usize,
+    max_fee_rate: MillisatoshiPerByte,
+) -> Result<ConsolidationResult, WalletError> {
+    let fee_per_byte = fee_per_byte(btc_network, &FeeOptions::default()).await?;
+    if fee_per_byte > max_fee_rate {
+        return Err(WalletError::FeeRateAboveMax {
+            fee_rate: fee_per_byte,
+            max_fee_rate,
+        });
+    }
+
+    let source = load_p2wpkh_source(
+        btc_network,
+        key_name.clone(),
+        derivation_path.clone(),
+        None,
+    )
+    .await?;
+
+    let max_inputs = max_inputs.min(MAX_CONSOLIDATION_INPUTS);
+    let (available_utxos, consolidated, fee, txid, spent, signed_transaction) = loop {
+        let available_utxos = consolidation_inputs(
+            read_wallet_state(|s| s.available_utxos(&source.utxos)),
+            max_inputs,
+            fee_per_byte,
+        );
+        if available_utxos.len() < 2 {
+            return Err(WalletError::NothingToConsolidate);
+        }
+
+        let total: Satoshi = available_utxos.iter().map(|utxo| utxo.value).sum();
+        let mut transaction = UnsignedTransaction {
+            inputs: available_utxos.iter().map(unsigned_input).collect(),
+            outputs: vec![ic_ckbtc_minter_syron::tx::TxOut {
+                address: source.address.clone(),
+                value: total,
+            }],
+            lock_time: 0,
+        };
+
+        // The inputs are fixed, so a single mock signature gives the final size.
+        let signed_transaction = sign_transaction_p2wpkh(
+            &source.public_key,
+            transaction.clone(),
+            String::from(""), // mock key name
+            vec![],           // mock derivation path
+            mock_signer_p2wpkh,
+        )
+        .await?;
+        let fee = (signed_transaction.vsize() as u64 * fee_per_byte) / 1000;
+        let consolidated = total.saturating_sub(fee);
+        check_dust(
+            consolidated,
+            bitcoin_address_dust_threshold(&source.address),
+        )?;
+        transaction.outputs[0].value = consolidated;
+
+        let txid = transaction.txid().to_string();
+        let spent: Vec<OutpointKey> = available_utxos
+            .iter()
+            .map(|utxo| outpoint_key(&utxo.outpoint))
+            .collect();
+
+        let signed_transaction = sign_transaction_p2wpkh(
+            &source.public_key,
+            transaction,
+            key_name.clone(),
+            derivation_path.clone(),
+            sign_with_ecdsa,
+        )
+        .await?;
+
+        // Another call may have spent the same UTXOs while we were signing.
+        if mutate_wallet_state(|s| {
+            s.try_lock(&spent, &source.address_str, &txid, ic_cdk::api::time())
+        }) {
+            break (
+                available_utxos,
+                consolidated,
+                fee,
+                txid,
+                spent,
+                signed_transaction,
+            );
+        }
+        print("UTXOs were spent concurrently, rebuilding transaction...");
+    };
+
+    print("Sending consolidation transaction...");
+    let signed_transaction_bytes = signed_transaction.serialize();
+    if let Err(err) =
+        bitcoin_api::send_transaction(btc_network, signed_transaction_bytes.clone()).await
+    {
+        mutate_wallet_state(|s| s.unlock(&spent));
+        return Err(err.into());
+    }
+
+    record_transaction(TransactionRecord {
+        txid: txid.clone(),
+        request: TransactionRequest::Consolidation {
+            max_inputs: max_inputs as u32,
+        },
+        caller: ic_cdk::caller(),
+        timestamp: ic_cdk::api::time(),
+        source_address: source.address_str.clone(),
+        derivation_path,
+        inputs: available_utxos.clone(),
+        outputs: vec![TransferOutput {
+            address: source.address_str.clone(),
+            value: consolidated,
+        }],
+        change_vout: Some(0),
+        fee,
+        fee_rate: fee_per_byte,
+        raw_transaction: signed_transaction_bytes,
+        last_broadcast: ic_cdk::api::time(),
+        rebroadcasts: 0,
+        stuck: false,
+        confirmed_height: None,
+        replaced_by: None,
+    });
+
+    let result = ConsolidationResult {
+        txid,
+        inputs: spent.len() as u32,
+        consolidated,
+        fee,
+        timestamp: ic_cdk::api::time(),
+    };
+    mutate_wallet_state(|s| s.last_consolidation = Some(result.clone()));
+    Ok(result)
+}
+
+// Returns the smallest of the given UTXOs, up to `max_inputs` of them, that
+// are worth more than the fee of spending them.
+fn consolidation_inputs(
+    mut utxos: Vec<Utxo>,
+    max_inputs: usize,
+    fee_per_byte: MillisatoshiPerByte,
+) -> Vec<Utxo> {
+    let input_fee = (P2WPKH_INPUT_VSIZE * fee_per_byte) / 1000;
+    utxos.retain(|utxo| utxo.value > input_fee);
+    utxos.sort_by(|a, b| a.value.cmp(&b.value));
+    utxos.truncate(max_inputs);
+    utxos
+}
+
+/// Replaces a transaction sent from one of the canister's P2WPKH addresses
+/// with one that spends the same inputs and pays the same destinations at
+/// `new_fee_rate`, taking the extra fee from the change output. The inputs
+/// are signed with the key the original was signed with.
+/// Returns the id of the replacement.
+pub async fn bump_fee_p2wpkh(
+    btc_network: BitcoinNetwork,
+    key_name: String,
+    txid: String,
+    new_fee_rate: MillisatoshiPerByte,
+) -> Result<String, WalletError> {
+    let _guard = BumpGuard::new(&txid)?;
+    let record = read_wallet_state(|s| s.transactions.get(&txid))
+        .ok_or_else(|| WalletError::UnknownTransaction(txid.clone()))?;
+    if let Some(height) = record.confirmed_height {
+        return Err(WalletError::AlreadyConfirmed { height });
+    }
+    if let Some(replacement) = record.replaced_by {
+        return Err(WalletError::AlreadyReplaced { replacement });
+    }
+    if new_fee_rate <= record.fee_rate {
+        return Err(WalletError::FeeRateTooLow {
+            fee_rate: new_fee_rate,
+            min_fee_rate: record.fee_rate + 1,
+        });
+    }
+    check_fee_rate_below_highest(btc_network, new_fee_rate).await?;
+
+    let derivation_path = record.derivation_path.clone();
+    let public_key = key_cache::ecdsa_public_key(key_name.clone(), derivation_path.clone()).await;
+    let address_str = public_key_to_p2wpkh_address(btc_network, &public_key);
+    // Only P2WPKH transactions can be rebuilt here.
+    if address_str != record.source_address {
+        return Err(WalletError::UnknownTransaction(txid));
+    }
+
+    let network = network_profile::profile(btc_network).minter_network();
+    let own_address = BitcoinAddress::parse(&address_str, network).unwrap();
+    let outputs = record
+        .outputs
+        .iter()
+        .map(|output| {
+            let address = BitcoinAddress::parse(&output.address, network).map_err(|err| {
+                BitcoinApiError::MalformedAddress(format!("{}: {:?}", output.address, err))
+            })?;
+            Ok(ic_ckbtc_minter_syron::tx::TxOut {
+                address,
+                value: output.value,
+            })
+        })
+        .collect::<Result<Vec<_>, WalletError>>()?;
+    let mut transaction = UnsignedTransaction {
+        inputs: record.inputs.iter().map(unsigned_input).collect(),
+        outputs,
+        lock_time: 0,
+    };
+
+    // BIP-125 requires the replacement to pay for its own size at the
+    // incremental relay fee on top of the fee of the original.
+    let signed_transaction = sign_transaction_p2wpkh(
+        &public_key,
+        transaction.clone(),
+        String::from(""), // mock key name
+        vec![],           // mock derivation path
+        mock_signer_p2wpkh,
+    )
+    .await?;
+    let vsize = signed_transaction.vsize() as u64;
+    let new_fee = std::cmp::max(
+        (vsize * new_fee_rate) / 1000,
+        record.fee + (vsize * INCREMENTAL_RELAY_FEE_PER_BYTE) / 1000,
+    );
+    let extra_fee = new_fee - record.fee;
+
+    let change_vout = record.change_vout.ok_or(WalletError::InsufficientFunds {
+        available: 0,
+        required: extra_fee,
+    })? as usize;
+    let change = transaction.outputs[change_vout].value;
+    if change < extra_fee {
+        return Err(WalletError::InsufficientFunds {
+            available: change,
+            required: extra_fee,
+        });
+    }
+    let mut fee = new_fee;
+    let remaining_change = change - extra_fee;
+    // Change below the dust threshold is left to the miners as extra fee.
+    if remaining_change >= bitcoin_address_dust_threshold(&own_address) {
+        transaction.outputs[change_vout].value = remaining_change;
+    } else {
+        transaction.outputs.remove(change_vout);
+        fee += remaining_change;
+    }
+
+    let new_txid = transaction.txid().to_string();
+    let signed_transaction = sign_transaction_p2wpkh(
+        &public_key,
+        transaction,
+        key_name,
+        derivation_path,
+        sign_with_ecdsa,
+    )
+    .await?;
+
+    print("Sending replacement transaction...");
+    let signed_transaction_bytes = signed_transaction.serialize();
+    bitcoin_api::send_transaction(btc_network, signed_transaction_bytes.clone()).await?;
+
+    let spent: Vec<OutpointKey> = record
+        .inputs
+        .iter()
+        .map(|utxo| outpoint_key(&utxo.outpoint))
+        .collect();
+    let has_change = signed_transaction.outputs.len() == record.outputs.len();
+    mutate_wallet_state(|s| {
+        s.unlock(&spent);
+        s.try_lock(&spent, &address_str, &new_txid, ic_cdk::api::time());
+        if let Some(mut original) = s.transactions.get(&txid) {
+            original.replaced_by = Some(new_txid.clone());
+            s.transactions.insert(txid.clone(), original);
+        }
+    });
+    record_transaction(TransactionRecord {
+        txid: new_txid.clone(),
+        request: TransactionRequest::BumpFee { txid },
+        caller: ic_cdk::caller(),
+        timestamp: ic_cdk::api::time(),
+        source_address: address_str,
+        derivation_path: record.derivation_path,
+        inputs: record.inputs,
+        outputs: signed_transaction
+            .outputs
+            .iter()
+            .map(|output| TransferOutput {
+                address: output.address.display(network),
+                value: output.value,
+            })
+            .collect(),
+        change_vout: if has_change { record.change_vout } else { None },
+        fee,
+        fee_rate: new_fee_rate,
+        raw_transaction: signed_transaction_bytes,
+        last_broadcast: ic_cdk::api::time(),
+        rebroadcasts: 0,
+        stuck: false,
+        confirmed_height: None,
+        replaced_by: None,
+    });
+
+    Ok(new_txid)
+}
+
+thread_local! {
+    // The transactions being replaced, so that two calls cannot both spend
+    // the inputs of the same original.
+    static BUMPING: RefCell<BTreeSet<String>> = RefCell::default();
+}
+
+struct BumpGuard {
+    txid: String,
+}
+
+impl BumpGuard {
+    fn new(txid: &str) -> Result<Self, WalletError> {
+        if !BUMPING.with(|b| b.borrow_mut().insert(txid.to_string())) {
+            return Err(WalletError::BumpInProgress(txid.to_string()));
+        }
+        Ok(Self {
+            txid: txid.to_string(),
+        })
+    }
+}
+
+// Also runs if the call traps after an await.
+impl Drop for BumpGuard {
+    fn drop(&mut self) {
+        BUMPING.with(|b| b.borrow_mut().remove(&self.txid));
+    }
+}
+
+/// Speeds up an unconfirmed deposit to an SSI vault with a child
+/// transaction (CPFP) that spends the deposit to the minter's deposit
+/// address of the SSI, paying enough fee for parent and child together to
+/// reach the target fee rate.
+///
+/// Once confirmed, the child's output is minted by `update_balance` like
+/// any other deposit. Returns the id of the child transaction.
+pub async fn accelerate_deposit(
+    btc_network: BitcoinNetwork,
+    key_name: String,
+    args: AccelerateDepositArgs,
+) -> Result<String, WalletError> {
+    let network = network_profile::profile(btc_network).minter_network();
+
+    check_fee_rate_below_highest(btc_network, args.target_fee_rate).await?;
+    check_min_fee_rate(args.target_fee_rate)?;
+
+    // `update_balance` mints what it finds at this address. Fetching it also
+    // makes sure the minter fetched its ECDSA public key.
+    let deposit_address_str = updates::get_btc_address::get_btc_address(GetBtcAddressArgs {
+        ssi: args.ssi.clone(),
+    })
+    .await;
+    let deposit_address = BitcoinAddress::parse(&deposit_address_str, network).map_err(|err| {
+        BitcoinApiError::MalformedAddress(format!("{}: {:?}", deposit_address_str, err))
+    })?;
+
+    // The deposit pays to the vault, whose key signs the child.
+    let account = vault_account(&args.ssi);
+    let ecdsa_public_key = read_state(|s| s.ecdsa_public_key.clone())
+        .expect("the ECDSA public key must be initialized by get_btc_address");
+    let public_key = address::derive_public_key(&ecdsa_public_key, &account);
+    let vault_address_str = BitcoinAddress::P2wpkhV0(tx::hash160(&public_key)).display(network);
+
+    let key = outpoint_key(&args.outpoint);
+    if let Some(lock) = read_wallet_state(|s| s.locked_utxos.get(&key).cloned()) {
+        return Err(WalletError::DepositAlreadyAccelerated { txid: lock.txid });
+    }
+    // The bitcoin API only lists outputs of transactions in blocks.
+    let vault_utxos = bitcoin_api::get_all_utxos(
+        btc_network,
+        vault_address_str.clone(),
+        None,
+        read_wallet_state(|s| s.max_utxo_pages()),
+    )
+    .await?
+    .utxos;
+    if vault_utxos
+        .iter()
+        .any(|utxo| outpoint_key(&utxo.outpoint) == key)
+    {
+        return Err(WalletError::DepositConfirmed);
+    }
+
+    let deposit = Utxo {
+        outpoint: args.outpoint.clone(),
+        value: args.value,
+        height: 0,
+    };
+    let mut transaction = UnsignedTransaction {
+        inputs: vec![unsigned_input(&deposit)],
+        outputs: vec![tx::TxOut {
+            address: deposit_address.clone(),
+            value: args.value,
+        }],
+        lock_time: 0,
+    };
+    let path: Vec<Vec<u8>> = address::derivation_path(&account)
+        .into_iter()
+        .map(|index| index.into_vec())
+        .collect();
+
+    // The input is fixed, so a mock signature gives the child's final size.
+    let signed_transaction = sign_transaction_p2wpkh(
+        &public_key,
+        transaction.clone(),
+        String::from(""), // mock key name
+        vec![],           // mock derivation path
+        mock_signer_p2wpkh,
+    )
+    .await?;
+    let fee = child_fee(&args, signed_transaction.vsize() as u64)?;
+    let value = args.value - fee;
+    if value < bitcoin_address_dust_threshold(&deposit_address) {
+        return Err(WalletError::InsufficientFunds {
+            available: args.value,
+            required: fee + bitcoin_address_dust_threshold(&deposit_address),
+        });
+    }
+    transaction.outputs[0].value = value;
+
+    let txid = transaction.txid().to_string();
+    let signed_transaction = sign_transaction_p2wpkh(
+        &public_key,
+        transaction,
+        key_name,
+        path.clone(),
+        sign_with_ecdsa,
+    )
+    .await?;
+
+    // Another call may have accelerated the deposit while we were signing.
+    if !mutate_wallet_state(|s| {
+        s.try_lock(&[key.clone()], &vault_address_str, &txid, ic_cdk::api::time())
+    }) {
+        let txid = read_wallet_state(|s| s.locked_utxos.get(&key).map(|lock| lock.txid.clone()));
+        return Err(WalletError::DepositAlreadyAccelerated {
+            txid: txid.unwrap_or_default(),
+        });
+    }
+
+    print("Sending child transaction...");
+    let signed_transaction_bytes = signed_transaction.serialize();
+    if let Err(err) =
+        bitcoin_api::send_transaction(btc_network, signed_transaction_bytes.clone()).await
+    {
+        mutate_wallet_state(|s| s.unlock(&[key]));
+        return Err(err.into());
+    }
+
+    record_transaction(TransactionRecord {
+        txid: txid.clone(),
+        caller: ic_cdk::caller(),
+        timestamp: ic_cdk::api::time(),
+        source_address: vault_address_str.clone(),
+        derivation_path: path,
+        inputs: vec![deposit],
+        outputs: vec![TransferOutput {
+            address: deposit_address_str,
+            value,
+        }],
+        change_vout: None,
+        fee,
+        fee_rate: args.target_fee_rate,
+        raw_transaction: signed_transaction_bytes,
+        last_broadcast: ic_cdk::api::time(),
+        rebroadcasts: 0,
+        stuck: false,
+        confirmed_height: None,
+        replaced_by: None,
+        request: TransactionRequest::AccelerateDeposit(args),
+    });
+
+    Ok(txid)
+}
+
+// Returns the fee a child of `child_vsize` spending the deposit pays, so
+// that it and its parent together pay the target fee rate.
+//
+// The parent's size and fee come from the caller, so they must be those of
+// a transaction relays accept, and the fee comes out of the deposit, of
+// which it takes at most a small share.
+fn child_fee(args: &AccelerateDepositArgs, child_vsize: u64) -> Result<Satoshi, WalletError> {
+    let parent_min_fee = (args.parent_vsize * MIN_RELAY_FEE_PER_BYTE) / 1000;
+    if args.parent_vsize < MIN_STANDARD_TX_VSIZE
+        || args.parent_vsize > MAX_STANDARD_TX_VSIZE
+        || args.parent_fee < parent_min_fee
+    {
+        return Err(WalletError::InvalidParent {
+            parent_vsize: args.parent_vsize,
+            parent_fee: args.parent_fee,
+        });
+    }
+
+    // The child pays for the parent's missing fee on top of its own.
+    let package_fee = ((args.parent_vsize + child_vsize) * args.target_fee_rate) / 1000;
+    let fee = std::cmp::max(
+        package_fee.saturating_sub(args.parent_fee),
+        (child_vsize * args.target_fee_rate) / 1000,
+    );
+    let max_fee = args.value / ACCELERATION_MAX_FEE_DIVISOR;
+    if fee > max_fee {
+        return Err(WalletError::FeeAboveMax { fee, max_fee });
+    }
+    Ok(fee)
+}
+
+/// Looks for the outputs of the pending transactions of the wallet in the
+/// UTXO sets of their addresses and records the height of each transaction
+/// that is found, then forgets the transactions settled long ago.
+pub async fn check_pending_transactions(btc_network: BitcoinNetwork) {
+    // Any output of a transaction tells it confirmed. Change goes back to the
+    // source address, which is listed in full. A transaction without change
+    // is looked for at its first destination, of which a single page is
+    // listed: the address can hold any number of UTXOs of others, and the
+    // bitcoin API lists the newest first.
+    let (sources, destinations) = mutate_wallet_state(|s| {
+        let mut sources = BTreeSet::new();
+        let mut destinations = BTreeSet::new();
+        for record in s.transactions_to_check(ic_cdk::api::time()) {
+            if record.change_vout.is_none() {
+                if let Some(output) = record.outputs.into_iter().next() {
+                    destinations.insert(output.address);
+                }
+            }
+            sources.insert(record.source_address);
+        }
+        (sources, destinations)
+    });
+
+    let max_pages = read_wallet_state(|s| s.max_utxo_pages());
+    let mut listed: BTreeMap<String, u32> = BTreeMap::new();
+    let in_full = sources.iter().map(|address| (address, max_pages));
+    let first_page = destinations
+        .difference(&sources)
+        .map(|address| (address, 1));
+    for (address, pages) in in_full.chain(first_page) {
+        match bitcoin_api::get_all_utxos(btc_network, address.clone(), None, pages).await {
+            Ok(listing) => {
+                for utxo in listing.utxos {
+                    let txid = vec_to_txid(utxo.outpoint.txid).to_string();
+                    listed.insert(txid, utxo.height);
+                }
+            }
+            Err(err) => print(&format!("Failed to fetch the UTXOs of {}: {:?}", address, err)),
+        }
+    }
+
+    mutate_wallet_state(|s| {
+        s.record_confirmations(&listed);
+        s.prune_settled_transactions(ic_cdk::api::time());
+    });
+}
+
+/// Broadcasts again the pending transactions that were last sent at least
+/// `config.interval_seconds` ago, and flags as stuck those that were already
+/// sent again `config.max_rebroadcasts` times.
+pub async fn rebroadcast_pending_transactions(
+    btc_network: BitcoinNetwork,
+    config: &RebroadcastConfig,
+) {
+    let now = ic_cdk::api::time();
+    let interval = config.interval_seconds.saturating_mul(1_000_000_000);
+    let due: Vec<(String, Vec<u8>)> = mutate_wallet_state(|s| {
+        let mut due = vec![];
+        let records: Vec<TransactionRecord> = s.transactions.iter().map(|(_, r)| r).collect();
+        for mut record in records {
+            if record.confirmed_height.is_some()
+                || record.replaced_by.is_some()
+                || record.stuck
+                || now < record.last_broadcast.saturating_add(interval)
+            {
+                continue;
+            }
+            if record.rebroadcasts >= config.max_rebroadcasts {
+                print(&format!(
+                    "Transaction {} is still unconfirmed after {} rebroadcasts.",
+                    record.txid, record.rebroadcasts
+                ));
+                record.stuck = true;
+                s.transactions.insert(record.txid.clone(), record);
+                continue;
+            }
+            record.rebroadcasts += 1;
+            record.last_broadcast = now;
+            due.push((record.txid.clone(), record.raw_transaction.clone()));
+            s.transactions.insert(record.txid.clone(), record);
+        }
+        due
+    });
+
+    for (txid, raw_transaction) in due {
+        print(&format!("Sending transaction {} again...", txid));
+        if let Err(err) = bitcoin_api::send_transaction(btc_network, raw_transaction).await {
+            print(&format!("Failed to send transaction {} again: {:?}", txid, err));
+        }
+    }
+}
+
+/// Returns the address of the SSI's vault without any call, or `None` if
+/// the minter did not fetch its ECDSA public key yet.
+pub fn get_vault_address(ssi: &str) -> Option<String> {
+    read_state(|s| {
+        s.ecdsa_public_key.as_ref()?;
+        Some(updates::get_btc_address::account_to_p2wpkh_address_from_state(
+            s,
+            &vault_account(ssi),
+        ))
+    })
+}
+
+// The minter account holding the deposits of an SSI. It must match the
+// account `get_btc_address` of the minter derives the vault from.
+fn vault_account(ssi: &str) -> Account {
+    Account {
+        owner: ic_cdk::id(),
+        subaccount: Some(compute_subaccount(1, ssi)),
+    }
+}
+
+// Returns the UTXOs with the given outpoints, in the order of `spent`.
+fn spent_utxos(utxos: &[Utxo], spent: &[OutpointKey]) -> Vec<Utxo> {
+    spent
+        .iter()
+        .filter_map(|outpoint| {
+            utxos
+                .iter()
+                .find(|utxo| &outpoint_key(&utxo.outpoint) == outpoint)
+        })
+        .cloned()
+        .collect()
+}
+
+fn record_transaction(record: TransactionRecord) {
+    mutate_wallet_state(|s| {
+        s.transactions.insert(record.txid.clone(), record);
+    });
+}
+
+// The canister's P2WPKH address at a derivation path, with what is needed
+// to build transactions spending from it.
+struct P2wpkhSource {
+    network: Network,
+    public_key: Vec<u8>,
+    address: BitcoinAddress,
+    address_str: String,
+    utxos: Vec<Utxo>,
 }
 
-pub async fn get_p2wpkh_address(
+async fn load_p2wpkh_source(
+    btc_network: BitcoinNetwork,
     key_name: String,
     derivation_path: Vec<Vec<u8>>,
-) -> String {
-    // Fetch the public key of the given derivation path.
-    let public_key = ecdsa_api::ecdsa_public_key(key_name, derivation_path).await;
+    min_confirmations: Option<u32>,
+) -> Result<P2wpkhSource, WalletError> {
+    // Fetch our public key, address, and UTXOs.
+    let public_key = key_cache::ecdsa_public_key(key_name, derivation_path).await;
+
+    let address_str = public_key_to_p2wpkh_address(btc_network, &public_key);
+
+    print("Fetching UTXOs...");
+    let listing = bitcoin_api::get_all_utxos(
+        btc_network,
+        address_str.clone(),
+        min_confirmations,
+        read_wallet_state(|s| s.max_utxo_pages()),
+    )
+    .await?;
+    release_utxo_locks(&listing);
+
+    let network = network_profile::profile(btc_network).minter_network();
+    let address = BitcoinAddress::parse(&address_str, network).unwrap();
+
+    Ok(P2wpkhSource {
+        network,
+        public_key,
+        address,
+        address_str,
+        utxos: listing.utxos,
+    })
+}
+
+/// Checks that a P2WPKH wallet can pay `amount` to `dst_address`.
+pub fn check_p2wpkh_payout(
+    btc_network: BitcoinNetwork,
+    dst_address: &str,
+    amount: Satoshi,
+) -> Result<(), WalletError> {
+    bitcoin_api::check_address(btc_network, dst_address)?;
+    let network = network_profile::profile(btc_network).minter_network();
+    let address = BitcoinAddress::parse(dst_address, network).map_err(|err| {
+        BitcoinApiError::MalformedAddress(format!("{}: {:?}", dst_address, err))
+    })?;
+    check_dust(amount, bitcoin_address_dust_threshold(&address))
+}
+
+fn parse_p2wpkh_destination(
+    source: &P2wpkhSource,
+    dst_address: &str,
+    amount: Satoshi,
+) -> Result<(BitcoinAddress, Satoshi), WalletError> {
+    let dst_address = BitcoinAddress::parse(dst_address, source.network).map_err(|err| {
+        BitcoinApiError::MalformedAddress(format!("{}: {:?}", dst_address, err))
+    })?;
+    check_dust(amount, bitcoin_address_dust_threshold(&dst_address))?;
+    Ok((dst_address, amount))
+}
+
+/// Chooses which UTXOs fund a transaction.
+pub trait CoinSelector {
+    /// Returns UTXOs whose total value is at least `target`, or `None` if
+    /// the given UTXOs do not suffice.
+    fn select<'a>(&self, utxos: &'a [Utxo], target: Satoshi) -> Option<Vec<&'a Utxo>>;
+}
+
+/// Spends the oldest UTXOs first.
+pub struct OldestFirst;
+
+impl CoinSelector for OldestFirst {
+    fn select<'a>(&self, utxos: &'a [Utxo], target: Satoshi) -> Option<Vec<&'a Utxo>> {
+        // The bitcoin API lists the newest UTXOs first.
+        accumulate(utxos.iter().rev(), target)
+    }
+}
+
+/// Spends the largest UTXOs first, which minimizes the number of inputs.
+pub struct LargestFirst;
+
+impl CoinSelector for LargestFirst {
+    fn select<'a>(&self, utxos: &'a [Utxo], target: Satoshi) -> Option<Vec<&'a Utxo>> {
+        let mut sorted: Vec<&Utxo> = utxos.iter().collect();
+        sorted.sort_by(|a, b| b.value.cmp(&a.value));
+        accumulate(sorted.into_iter(), target)
+    }
+}
+
+/// Spends the smallest UTXOs first, merging dust deposits into larger
+/// outputs while paying out.
+pub struct Consolidation;
+
+impl CoinSelector for Consolidation {
+    fn select<'a>(&self, utxos: &'a [Utxo], target: Satoshi) -> Option<Vec<&'a Utxo>> {
+        let mut sorted: Vec<&Utxo> = utxos.iter().collect();
+        sorted.sort_by(|a, b| a.value.cmp(&b.value));
+        accumulate(sorted.into_iter(), target)
+    }
+}
+
+/// Searches for a set of UTXOs that covers the target without leaving a
+/// change output, i.e. exceeding it by less than `cost_of_change`. Falls
+/// back to `LargestFirst` if there is no such set.
+pub struct BranchAndBound {
+    /// What a change output costs: the fee for its own size plus that of
+    /// the input spending it later.
+    pub cost_of_change: Satoshi,
+}
+
+// The maximum number of selections `BranchAndBound` explores.
+const BNB_MAX_TRIES: usize = 100_000;
+
+impl CoinSelector for BranchAndBound {
+    fn select<'a>(&self, utxos: &'a [Utxo], target: Satoshi) -> Option<Vec<&'a Utxo>> {
+        let mut sorted: Vec<&Utxo> = utxos.iter().collect();
+        sorted.sort_by(|a, b| b.value.cmp(&a.value));
+
+        // remaining[i] is the total value of sorted[i..].
+        let mut remaining = vec![0; sorted.len() + 1];
+        for i in (0..sorted.len()).rev() {
+            remaining[i] = remaining[i + 1] + sorted[i].value;
+        }
+
+        let mut search = BnbSearch {
+            values: sorted.iter().map(|utxo| utxo.value).collect(),
+            remaining,
+            target,
+            upper_bound: target.saturating_add(self.cost_of_change),
+            selected: vec![],
+            tries: 0,
+        };
+        if search.run() {
+            return Some(search.selected.into_iter().map(|i| sorted[i]).collect());
+        }
+        LargestFirst.select(utxos, target)
+    }
+}
 
-    ic_ckbtc_minter_syron::address::network_and_public_key_to_p2wpkh(&public_key)
+struct BnbSearch {
+    values: Vec<Satoshi>,
+    remaining: Vec<Satoshi>,
+    target: Satoshi,
+    upper_bound: Satoshi,
+    selected: Vec<usize>,
+    tries: usize,
 }
 
-/// Sends a transaction to the network that transfers the given amount to the
-/// given destination, where the source of the funds is the canister itself
-/// at the given derivation path.
-pub async fn send(
-    network: BitcoinNetwork,
-    derivation_path: Vec<Vec<u8>>,
-    key_name: String,
-    dst_address: String,
-    amount: Satoshi,
-) -> Txid {
-    // Get fee percentiles from previous transactions to estimate our own fee.
-    let fee_percentiles = bitcoin_api::get_current_fee_percentiles(network).await;
+impl BnbSearch {
+    // Explores the selections depth first, including each value before
+    // excluding it, until one lands between the target and the upper bound.
+    // `selected` is the path to the current branch, so backtracking drops
+    // its last value and moves on to the branch excluding it.
+    fn run(&mut self) -> bool {
+        let mut index = 0;
+        let mut total = 0;
+        loop {
+            self.tries += 1;
+            if self.tries > BNB_MAX_TRIES {
+                return false;
+            }
+
+            let dead_end = if total > self.upper_bound {
+                true
+            } else if total >= self.target {
+                return true;
+            } else {
+                index == self.values.len() || total + self.remaining[index] < self.target
+            };
+            if !dead_end {
+                self.selected.push(index);
+                total += self.values[index];
+                index += 1;
+                continue;
+            }
+
+            match self.selected.pop() {
+                Some(last) => {
+                    total -= self.values[last];
+                    index = last + 1;
+                }
+                None => return false,
+            }
+        }
+    }
+}
 
-    let fee_per_byte = if fee_percentiles.is_empty() {
-        // There are no fee percentiles. This case can only happen on a regtest
-        // network where there are no non-coinbase transactions. In this case,
-        // we use a default of 2000 millisatoshis/byte (i.e. 2 satoshi/byte)
-        2000
+// Takes UTXOs in the given order until their total value reaches `target`.
+fn accumulate<'a>(
+    utxos: impl Iterator<Item = &'a Utxo>,
+    target: Satoshi,
+) -> Option<Vec<&'a Utxo>> {
+    let mut selected = vec![];
+    let mut total = 0;
+    for utxo in utxos {
+        if total >= target {
+            break;
+        }
+        total += utxo.value;
+        selected.push(utxo);
+    }
+    if total >= target {
+        Some(selected)
     } else {
-        // Choose the 50th percentile for sending fees.
-        fee_percentiles[50]
-    };
-
-    // Fetch our public key, P2PKH address, and UTXOs.
-    let own_public_key =
-        ecdsa_api::ecdsa_public_key(key_name.clone(), derivation_path.clone()).await;
-    let own_address = public_key_to_p2pkh_address(network, &own_public_key);
-
-    print("Fetching UTXOs...");
-    // Note that pagination may have to be used to get all UTXOs for the given address.
-    // For the sake of simplicity, it is assumed here that the `utxo` field in the response
-    // contains all UTXOs.
-    let own_utxos = bitcoin_api::get_utxos(network, own_address.clone())
-        .await
-        .utxos;
-
-    let own_address = Address::from_str(&own_address).unwrap();
-    let dst_address = Address::from_str(&dst_address).unwrap();
-
-    // Build the transaction that sends `amount` to the destination address.
-    let transaction = build_transaction(
-        &own_public_key,
-        &own_address,
-        &own_utxos,
-        &dst_address,
-        amount,
-        fee_per_byte,
-    )
-    .await;
+        None
+    }
+}
 
-    let tx_bytes = transaction.serialize();
-    print(&format!("Transaction to sign: {}", hex::encode(tx_bytes)));
+// Returns what a change output costs at `fee_per_byte`: the fee for its own
+// size plus that of the input spending it later.
+fn cost_of_change(
+    output_vsize: u64,
+    input_vsize: u64,
+    fee_per_byte: MillisatoshiPerByte,
+) -> Satoshi {
+    ((output_vsize + input_vsize) * fee_per_byte) / 1000
+}
 
-    // Sign the transaction.
-    let signed_transaction = sign_transaction_p2pkh(
-        &own_public_key,
-        &own_address,
-        transaction,
-        key_name,
-        derivation_path,
-        ecdsa_api::sign_with_ecdsa,
-    )
-    .await;
+// Returns the coin selector implementing the given strategy.
+fn coin_selector(strategy: CoinSelection, cost_of_change: Satoshi) -> Box<dyn CoinSelector> {
+    match strategy {
+        CoinSelection::OldestFirst => Box::new(OldestFirst),
+        CoinSelection::LargestFirst => Box::new(LargestFirst),
+        CoinSelection::BranchAndBound => Box::new(BranchAndBound { cost_of_change }),
+        CoinSelection::Consolidation => Box::new(Consolidation),
+    }
+}
 
-    let signed_transaction_bytes = signed_transaction.serialize();
-    print(&format!(
-        "Signed transaction: {}",
-        hex::encode(&signed_transaction_bytes)
-    ));
+fn select_utxos<'a>(
+    selector: &dyn CoinSelector,
+    utxos: &'a [Utxo],
+    target: Satoshi,
+) -> Result<Vec<&'a Utxo>, WalletError> {
+    selector
+        .select(utxos, target)
+        .ok_or_else(|| WalletError::InsufficientFunds {
+            available: utxos.iter().map(|utxo| utxo.value).sum(),
+            required: target,
+        })
+}
 
-    print("Sending transaction...");
-    bitcoin_api::send_transaction(network, signed_transaction_bytes).await;
-    print("Done");
+// Returns the fee rate to pay according to the caller's fee options.
+async fn fee_per_byte(
+    network: BitcoinNetwork,
+    fee_options: &FeeOptions,
+) -> Result<MillisatoshiPerByte, WalletError> {
+    if let Some(fee_rate) = fee_options.fee_rate {
+        check_min_fee_rate(fee_rate)?;
+        return Ok(fee_rate);
+    }
 
-    signed_transaction.txid()
-}
+    let percentile = fee_options.fee_percentile.unwrap_or(DEFAULT_FEE_PERCENTILE);
+    if percentile > 99 {
+        return Err(WalletError::InvalidFeePercentile(percentile));
+    }
 
-pub async fn send_p2wpkh(
-    btc_network: BitcoinNetwork,
-    derivation_path: Vec<Vec<u8>>,
-    key_name: String,
-    dst_address: String,
-    amount: Satoshi,
-) -> [u8;32] {
     // Get fee percentiles from previous transactions to estimate our own fee.
-    let fee_percentiles = bitcoin_api::get_current_fee_percentiles(btc_network).await;
-
-    let fee_per_byte = if fee_percentiles.is_empty() {
-        // There are no fee percentiles. This case can only happen on a regtest
-        // network where there are no non-coinbase transactions. In this case,
-        // we use a default of 2000 millisatoshis/byte (i.e. 2 satoshi/byte)
-        2000
-    } else {
-        // Choose the 50th percentile for sending fees.
-        fee_percentiles[50]
-    };
+    let fee_percentiles = bitcoin_api::get_current_fee_percentiles(network).await?;
 
-    // Fetch our public key, address, and UTXOs.
-    let own_public_key =
-        ecdsa_api::ecdsa_public_key(key_name.clone(), derivation_path.clone()).await;
-    
-    //@review (mainnet)
-    let own_address = ic_ckbtc_minter_syron::address::network_and_public_key_to_p2wpkh(&own_public_key);
+    Ok(match fee_percentiles.last() {
+        None => network_profile::profile(network).fallback_fee_rate,
+        Some(highest) => *fee_percentiles
+            .get(percentile as usize)
+            .unwrap_or(highest),
+    })
+}
 
-    print("Fetching UTXOs...");
-    // Note that pagination may have to be used to get all UTXOs for the given address.
-    // For the sake of simplicity, it is assumed here that the `utxo` field in the response
-    // contains all UTXOs.
-    let own_utxos = bitcoin_api::get_utxos(btc_network, own_address.clone())
-        .await
-        .utxos;
-
-    let network: Network = match btc_network {
-        BitcoinNetwork::Mainnet => Network::Mainnet,
-        BitcoinNetwork::Testnet => Network::Testnet,
-        BitcoinNetwork::Regtest => Network::Regtest,
-    };
-    let own_address = BitcoinAddress::parse(&own_address, network).unwrap();
-    let dst_address = BitcoinAddress::parse(&dst_address, network).unwrap();
-    
-    // Build the transaction that sends `amount` to the destination address.
-    let transaction = build_unsigned_transaction(
-        &own_public_key,
-        own_address,
-        &own_utxos,
-        dst_address,
-        amount,
-        fee_per_byte,
-    )
-    .await;
+fn check_min_fee_rate(fee_rate: MillisatoshiPerByte) -> Result<(), WalletError> {
+    if fee_rate < MIN_RELAY_FEE_PER_BYTE {
+        return Err(WalletError::FeeRateTooLow {
+            fee_rate,
+            min_fee_rate: MIN_RELAY_FEE_PER_BYTE,
+        });
+    }
+    Ok(())
+}
 
-    // Sign the transaction.
-    let signed_transaction: SignedTransaction = sign_transaction_p2wpkh(
-        &own_public_key,
-        transaction,
-        key_name,
-        derivation_path,
-    )
-    .await.unwrap();
+// Paying more than the highest recent fee rate cannot confirm a transaction
+// any sooner, so a higher `fee_rate` is most likely a mistake.
+async fn check_fee_rate_below_highest(
+    btc_network: BitcoinNetwork,
+    fee_rate: MillisatoshiPerByte,
+) -> Result<(), WalletError> {
+    let fee_percentiles = bitcoin_api::get_current_fee_percentiles(btc_network).await?;
+    let max_fee_rate = fee_percentiles
+        .last()
+        .copied()
+        .unwrap_or(network_profile::profile(btc_network).fallback_fee_rate);
+    if fee_rate > max_fee_rate {
+        return Err(WalletError::FeeRateAboveMax {
+            fee_rate,
+            max_fee_rate,
+        });
+    }
+    Ok(())
+}
 
-    print("Sending transaction...");
-    let signed_transaction_bytes = signed_transaction.serialize();
-    bitcoin_api::send_transaction(btc_network, signed_transaction_bytes).await;
-    print("Done");
+fn check_max_fee(fee: Satoshi, fee_options: &FeeOptions) -> Result<(), WalletError> {
+    match fee_options.max_fee {
+        Some(max_fee) if fee > max_fee => Err(WalletError::FeeAboveMax { fee, max_fee }),
+        _ => Ok(()),
+    }
+}
 
-    signed_transaction.wtxid()
+// Returns the confirmations spent UTXOs need, the network's default unless
+// the request sets them.
+fn min_confirmations(
+    network: BitcoinNetwork,
+    requested: Option<u32>,
+) -> Result<Option<u32>, WalletError> {
+    let min_confirmations =
+        requested.unwrap_or(network_profile::profile(network).default_min_confirmations);
+    if min_confirmations > MAX_MIN_CONFIRMATIONS {
+        return Err(WalletError::MinConfirmationsAboveMax {
+            min_confirmations,
+            max_min_confirmations: MAX_MIN_CONFIRMATIONS,
+        });
+    }
+    Ok(Some(min_confirmations))
 }
 
+// Releases the locks whose spending transaction is deep enough below the
+// tip the UTXOs were listed at, or no longer pending.
+fn release_utxo_locks(listing: &bitcoin_api::UtxoListing) {
+    mutate_wallet_state(|s| s.release_locks(listing.tip_height, ic_cdk::api::time()));
+}
 
 // Builds a transaction to send the given `amount` of satoshis to the
-// destination address.
+// destination address. Returns the transaction along with its fee.
 async fn build_transaction(
-    own_public_key: &[u8],
+    key: &SpendingKey,
     own_address: &Address,
     own_utxos: &[Utxo],
     dst_address: &Address,
     amount: Satoshi,
     fee_per_byte: MillisatoshiPerByte,
-) -> Transaction {
-    // We have a chicken-and-egg problem where we need to know the length
-    // of the transaction in order to compute its proper fee, but we need
-    // to know the proper fee in order to figure out the inputs needed for
-    // the transaction.
-    //
-    // We solve this problem iteratively. We start with a fee of zero, build
-    // and sign a transaction, see what its size is, and then update the fee,
-    // rebuild the transaction, until the fee is set to the correct amount.
+    selector: &dyn CoinSelector,
+) -> Result<(Transaction, Satoshi), WalletError> {
     print("Building transaction...");
-    let mut total_fee = 0;
-    loop {
-        let transaction =
-            build_transaction_with_fee(own_utxos, own_address, dst_address, amount, total_fee)
-                .expect("Error building transaction.");
+    let (transaction, fee) = settle_fee(fee_per_byte, |total_fee| async move {
+        let (transaction, fee) = build_transaction_with_fee(
+            own_utxos,
+            own_address,
+            dst_address,
+            amount,
+            total_fee,
+            selector,
+        )?;
 
         // Sign the transaction. In this case, we only care about the size
         // of the signed transaction, so we use a mock signer here for efficiency.
-        let signed_transaction = sign_transaction_p2pkh(
-            own_public_key,
-            own_address,
-            transaction.clone(),
-            String::from(""), // mock key name
-            vec![],           // mock derivation path
-            mock_signer,
-        )
-        .await;
-
-        let signed_tx_bytes_len = signed_transaction.serialize().len() as u64;
-
-        if (signed_tx_bytes_len * fee_per_byte) / 1000 == total_fee {
-            print(&format!("Transaction built with fee {}.", total_fee));
-            return transaction;
-        } else {
-            total_fee = (signed_tx_bytes_len * fee_per_byte) / 1000;
-        }
-    }
+        // Its signatures are as long as real ones can be.
+        let signed_transaction = match key {
+            SpendingKey::P2pkh { public_key } => {
+                sign_transaction_p2pkh(
+                    public_key,
+                    own_address,
+                    transaction.clone(),
+                    String::from(""), // mock key name
+                    vec![],           // mock derivation path
+                    mock_signer,
+                )
+                .await?
+            }
+            SpendingKey::P2tr => {
+                sign_transaction_p2tr(
+                    own_address,
+                    own_utxos,
+                    transaction.clone(),
+                    String::from(""), // mock key name
+                    vec![],           // mock derivation path
+                    mock_signer_schnorr,
+                )
+                .await?
+            }
+        };
+
+        let signed_tx_vsize = signed_transaction.vsize() as u64;
+        Ok((transaction, fee, signed_tx_vsize))
+    })
+    .await?;
+    print(&format!("Transaction built with fee {}.", fee));
+    Ok((transaction, fee))
 }
 
 async fn build_unsigned_transaction(
     own_public_key: &[u8],
     own_address: BitcoinAddress,
     own_utxos: &[Utxo],
-    dst_address: BitcoinAddress,
-    amount: Satoshi,
+    destinations: &[(BitcoinAddress, Satoshi)],
     fee_per_byte: MillisatoshiPerByte,
-) -> UnsignedTransaction {
-    // We have a chicken-and-egg problem where we need to know the length
-    // of the transaction in order to compute its proper fee, but we need
-    // to know the proper fee in order to figure out the inputs needed for
-    // the transaction.
-    //
-    // We solve this problem iteratively. We start with a fee of zero, build
-    // and sign a transaction, see what its size is, and then update the fee,
-    // rebuild the transaction, until the fee is set to the correct amount.
+    selector: &dyn CoinSelector,
+) -> Result<(UnsignedTransaction, Satoshi), WalletError> {
     print("Building transaction...");
+    let (transaction, fee) = settle_fee(fee_per_byte, |total_fee| {
+        let own_address = own_address.clone();
+        async move {
+            let (transaction, fee) = build_unsigned_tx_with_fee(
+                own_utxos,
+                own_address,
+                destinations,
+                total_fee,
+                selector,
+            )?;
+
+            // Sign the transaction. In this case, we only care about the size
+            // of the signed transaction, so we use a mock signer here for efficiency.
+            let signed_transaction = sign_transaction_p2wpkh(
+                own_public_key,
+                transaction.clone(),
+                String::from(""), // mock key name
+                vec![],           // mock derivation path
+                mock_signer_p2wpkh,
+            )
+            .await?;
+
+            let signed_tx_vsize = signed_transaction.vsize() as u64;
+            Ok((transaction, fee, signed_tx_vsize))
+        }
+    })
+    .await?;
+    print(&format!("Transaction built with fee {}.", fee));
+    Ok((transaction, fee))
+}
+
+// We have a chicken-and-egg problem where we need to know the size of the
+// transaction in order to compute its proper fee, but we need to know the
+// proper fee in order to figure out the inputs needed for the transaction.
+//
+// We solve this problem iteratively. `build` returns the transaction paying
+// at least the given fee, the fee it actually pays and its signed virtual
+// size. We start with a fee of zero and raise it to what the last size
+// requires until a transaction pays for its own size. Only then is its
+// change final: a higher fee can leave change below the dust threshold and
+// shrink the transaction, so waiting for an exact match could alternate
+// between the two sizes forever.
+async fn settle_fee<T, Build, Fut>(
+    fee_per_byte: MillisatoshiPerByte,
+    mut build: Build,
+) -> Result<(T, Satoshi), WalletError>
+where
+    Build: FnMut(Satoshi) -> Fut,
+    Fut: std::future::Future<Output = Result<(T, Satoshi, u64), WalletError>>,
+{
     let mut total_fee = 0;
     loop {
-        let transaction =
-            build_unsigned_tx_with_fee(own_utxos, own_address.clone(), dst_address.clone(), amount, total_fee)
-                .expect("Error building transaction.");
-
-        // Sign the transaction. In this case, we only care about the size
-        // of the signed transaction, so we use a mock signer here for efficiency.
-        let signed_transaction = sign_transaction_p2wpkh(
-            own_public_key,
-            transaction.clone(),
-            String::from(""), // mock key name
-            vec![],           // mock derivation path
-        )
-        .await.unwrap();
-
-        let signed_tx_bytes_len = signed_transaction.serialize().len() as u64;
-
-        if (signed_tx_bytes_len * fee_per_byte) / 1000 == total_fee {
-            print(&format!("Transaction built with fee {}.", total_fee));
-            return transaction;
-        } else {
-            total_fee = (signed_tx_bytes_len * fee_per_byte) / 1000;
+        let (transaction, fee, signed_tx_vsize) = build(total_fee).await?;
+        let required_fee = (signed_tx_vsize * fee_per_byte) / 1000;
+        if required_fee <= total_fee {
+            return Ok((transaction, fee));
         }
+        total_fee = required_fee;
     }
 }
 
@@ -301,32 +1869,14 @@ fn build_transaction_with_fee(
     dst_address: &Address,
     amount: u64,
     fee: u64,
-) -> Result<Transaction, String> {
-    // Assume that any amount below this threshold is dust.
-    //@review (mainnet)
-    const DUST_THRESHOLD: u64 = 0;
-
-    // Select which UTXOs to spend. We naively spend the oldest available UTXOs,
-    // even if they were previously spent in a transaction. This isn't a
-    // problem as long as at most one transaction is created per block and
-    // we're using min_confirmations of 1.
-    let mut utxos_to_spend = vec![];
-    let mut total_spent = 0;
-    for utxo in own_utxos.iter().rev() {
-        total_spent += utxo.value;
-        utxos_to_spend.push(utxo);
-        if total_spent >= amount + fee {
-            // We have enough inputs to cover the amount we want to spend.
-            break;
-        }
-    }
+    selector: &dyn CoinSelector,
+) -> Result<(Transaction, Satoshi), WalletError> {
+    check_dust(amount, dust_threshold(dst_address))?;
 
-    if total_spent < amount + fee {
-        return Err(format!(
-            "Insufficient balance: {}, trying to transfer {} satoshi with fee {}",
-            total_spent, amount, fee
-        ));
-    }
+    // Select which UTXOs to spend. UTXOs locked by in-flight transactions
+    // are filtered out by the caller.
+    let utxos_to_spend = select_utxos(selector, own_utxos, amount + fee)?;
+    let total_spent: u64 = utxos_to_spend.iter().map(|utxo| utxo.value).sum();
 
     let inputs: Vec<TxIn> = utxos_to_spend
         .into_iter()
@@ -335,7 +1885,7 @@ fn build_transaction_with_fee(
                 txid: Txid::from_hash(Hash::from_slice(&utxo.outpoint.txid).unwrap()),
                 vout: utxo.outpoint.vout,
             },
-            sequence: 0xffffffff,
+            sequence: RBF_SEQUENCE,
             witness: Witness::new(),
             script_sig: Script::new(),
         })
@@ -348,90 +1898,111 @@ fn build_transaction_with_fee(
 
     let remaining_amount = total_spent - amount - fee;
 
-    if remaining_amount >= DUST_THRESHOLD {
+    // Change below the dust threshold is left to the miners as extra fee.
+    let mut effective_fee = fee;
+    if remaining_amount >= dust_threshold(own_address) {
         outputs.push(TxOut {
             script_pubkey: own_address.script_pubkey(),
             value: remaining_amount,
         });
+    } else {
+        effective_fee += remaining_amount;
     }
 
-    Ok(Transaction {
-        input: inputs,
-        output: outputs,
-        lock_time: 0,
-        version: 1,
-    })
+    Ok((
+        Transaction {
+            input: inputs,
+            output: outputs,
+            lock_time: 0,
+            version: 1,
+        },
+        effective_fee,
+    ))
+}
+
+// Returns the dust threshold of an output paying to the given address.
+fn dust_threshold(address: &Address) -> u64 {
+    network_profile::current().dust_limits.of_address_type(address.address_type())
 }
 
-fn vec_to_txid(vec: Vec<u8>) -> ic_ckbtc_minter_syron::tx::Txid {
+fn bitcoin_address_dust_threshold(address: &BitcoinAddress) -> u64 {
+    network_profile::current().dust_limits.of_bitcoin_address(address)
+}
+
+fn check_dust(amount: u64, dust_threshold: u64) -> Result<(), WalletError> {
+    if amount < dust_threshold {
+        return Err(WalletError::AmountBelowDust {
+            amount,
+            dust_threshold,
+        });
+    }
+    Ok(())
+}
+
+pub(crate) fn vec_to_txid(vec: Vec<u8>) -> ic_ckbtc_minter_syron::tx::Txid {
     let bytes: [u8; 32] = std::convert::TryInto::try_into(vec).expect("Can't convert to [u8; 32]");
     bytes.into()
 }
 
+fn unsigned_input(utxo: &Utxo) -> UnsignedInput {
+    UnsignedInput {
+        previous_output: ic_ckbtc_minter_syron::tx::OutPoint {
+            txid: vec_to_txid(utxo.outpoint.txid.clone()),
+            vout: utxo.outpoint.vout,
+        },
+        value: utxo.value,
+        sequence: RBF_SEQUENCE,
+    }
+}
+
 fn build_unsigned_tx_with_fee(
     own_utxos: &[Utxo],
     own_address: BitcoinAddress,
-    dst_address: BitcoinAddress,
-    amount: u64,
+    destinations: &[(BitcoinAddress, Satoshi)],
     fee: u64,
-) -> Result<UnsignedTransaction, String> {
-    // Assume that any amount below this threshold is dust.
-    //@review (mainnet)
-    const DUST_THRESHOLD: u64 = 0;
-
-    // Select which UTXOs to spend. We naively spend the oldest available UTXOs,
-    // even if they were previously spent in a transaction. This isn't a
-    // problem as long as at most one transaction is created per block and
-    // we're using min_confirmations of 1.
-    let mut utxos_to_spend = vec![];
-    let mut total_spent = 0;
-    for utxo in own_utxos.iter().rev() {
-        total_spent += utxo.value;
-        utxos_to_spend.push(utxo);
-        if total_spent >= amount + fee {
-            // We have enough inputs to cover the amount we want to spend.
-            break;
-        }
+    selector: &dyn CoinSelector,
+) -> Result<(UnsignedTransaction, Satoshi), WalletError> {
+    for (dst_address, amount) in destinations {
+        check_dust(*amount, bitcoin_address_dust_threshold(dst_address))?;
     }
+    let amount: u64 = destinations.iter().map(|(_, amount)| amount).sum();
 
-    if total_spent < amount + fee {
-        return Err(format!(
-            "Insufficient balance: {}, trying to transfer {} satoshi with fee {}",
-            total_spent, amount, fee
-        ));
-    }
+    // Select which UTXOs to spend. UTXOs locked by in-flight transactions
+    // are filtered out by the caller.
+    let utxos_to_spend = select_utxos(selector, own_utxos, amount + fee)?;
+    let total_spent: u64 = utxos_to_spend.iter().map(|utxo| utxo.value).sum();
 
-    let inputs: Vec<UnsignedInput> = utxos_to_spend
-        .into_iter()
-        .map(|utxo| UnsignedInput {
-            previous_output: ic_ckbtc_minter_syron::tx::OutPoint {
-                txid: vec_to_txid(utxo.outpoint.txid.clone()),
-                vout: utxo.outpoint.vout,
-            },
-            value: utxo.value,
-            sequence: 0xffffffff,
+    let inputs: Vec<UnsignedInput> = utxos_to_spend.into_iter().map(unsigned_input).collect();
+
+    let mut outputs: Vec<ic_ckbtc_minter_syron::tx::TxOut> = destinations
+        .iter()
+        .map(|(dst_address, amount)| ic_ckbtc_minter_syron::tx::TxOut {
+            address: dst_address.clone(),
+            value: *amount,
         })
         .collect();
 
-    let mut outputs: Vec<ic_ckbtc_minter_syron::tx::TxOut> = vec![ic_ckbtc_minter_syron::tx::TxOut {
-        address: dst_address,
-        value: amount,
-    }];
-
     let remaining_amount = total_spent - amount - fee;
 
-    if remaining_amount >= DUST_THRESHOLD {
+    // Change below the dust threshold is left to the miners as extra fee.
+    let mut effective_fee = fee;
+    if remaining_amount >= bitcoin_address_dust_threshold(&own_address) {
         outputs.push(ic_ckbtc_minter_syron::tx::TxOut {
             address: own_address,
             value: remaining_amount,
         });
+    } else {
+        effective_fee += remaining_amount;
     }
 
-    Ok(UnsignedTransaction {
-        inputs,
-        outputs,
-        lock_time: 0,
-    })
+    Ok((
+        UnsignedTransaction {
+            inputs,
+            outputs,
+            lock_time: 0,
+        },
+        effective_fee,
+    ))
 }
 
 // Sign a bitcoin transaction.
@@ -447,10 +2018,10 @@ async fn sign_transaction_p2pkh<SignFun, Fut>(
     key_name: String,
     derivation_path: Vec<Vec<u8>>,
     signer: SignFun,
-) -> Transaction
+) -> Result<Transaction, WalletError>
 where
     SignFun: Fn(String, Vec<Vec<u8>>, Vec<u8>) -> Fut,
-    Fut: std::future::Future<Output = Vec<u8>>,
+    Fut: std::future::Future<Output = CallResult<Vec<u8>>>,
 {
     // Verify that our own address is P2PKH.
     assert_eq!(
@@ -464,7 +2035,7 @@ where
         let sighash =
             txclone.signature_hash(index, &own_address.script_pubkey(), SIG_HASH_TYPE.to_u32());
 
-        let signature = signer(key_name.clone(), derivation_path.clone(), sighash.to_vec()).await;
+        let signature = signer(key_name.clone(), derivation_path.clone(), sighash.to_vec()).await?;
 
         // Convert signature to DER.
         let der_signature = sec1_to_der(signature);
@@ -478,7 +2049,7 @@ where
         input.witness.clear();
     }
 
-    transaction
+    Ok(transaction)
 }
 
 fn convert_to_bytebufs(data: Vec<Vec<u8>>) -> Vec<ByteBuf> {
@@ -488,12 +2059,17 @@ fn convert_to_bytebufs(data: Vec<Vec<u8>>) -> Vec<ByteBuf> {
 }
 
 // 2.B `own_address` is a P2WPKH address.
-async fn sign_transaction_p2wpkh(
+async fn sign_transaction_p2wpkh<SignFun, Fut>(
     own_public_key: &[u8],
     unsigned_tx: UnsignedTransaction,
     key_name: String,
-    derivation_path: Vec<Vec<u8>>
-) -> Result<SignedTransaction, CallError> {
+    derivation_path: Vec<Vec<u8>>,
+    signer: SignFun,
+) -> Result<SignedTransaction, CallError>
+where
+    SignFun: Fn(String, DerivationPath, [u8; 32]) -> Fut,
+    Fut: std::future::Future<Output = Result<Vec<u8>, CallError>>,
+{
     // Verify that our own address is P2WPKH. @review (test)
     // assert_eq!(
     //     own_address.address_type(),
@@ -505,8 +2081,6 @@ async fn sign_transaction_p2wpkh(
     let sighasher = tx::TxSigHasher::new(&unsigned_tx);
 
     let path = convert_to_bytebufs(derivation_path);
- 
-    let key_name = "test_key_1".to_string(); //@review (sign) key_name should not be empty
 
     for input in &unsigned_tx.inputs {
         let outpoint = &input.previous_output;
@@ -517,11 +2091,11 @@ async fn sign_transaction_p2wpkh(
         let sighash = sighasher.sighash(&input, &pkhash);
 
         let sec1_signature =
-            sign_with_ecdsa(key_name.clone(), DerivationPath::new(path.clone()), sighash)
-            .await;
+            signer(key_name.clone(), DerivationPath::new(path.clone()), sighash)
+            .await?;
 
         signed_inputs.push(SignedInput {
-            signature: EncodedSignature::from_sec1(&sec1_signature.unwrap()),
+            signature: EncodedSignature::from_sec1(&sec1_signature),
             pubkey,
             previous_output: outpoint.clone(),
             sequence: input.sequence,
@@ -535,6 +2109,79 @@ async fn sign_transaction_p2wpkh(
     })
 }
 
+// 2.C `own_address` is a P2TR address spent through the key path.
+//
+// `own_utxos` must contain the outputs spent by the transaction, since
+// taproot signatures commit to the amounts of all the inputs.
+async fn sign_transaction_p2tr<SignFun, Fut>(
+    own_address: &Address,
+    own_utxos: &[Utxo],
+    mut transaction: Transaction,
+    key_name: String,
+    derivation_path: Vec<Vec<u8>>,
+    signer: SignFun,
+) -> Result<Transaction, WalletError>
+where
+    SignFun: Fn(String, Vec<Vec<u8>>, Vec<u8>) -> Fut,
+    Fut: std::future::Future<Output = CallResult<Vec<u8>>>,
+{
+    // Verify that our own address is P2TR.
+    assert_eq!(
+        own_address.address_type(),
+        Some(AddressType::P2tr),
+        "This function supports signing p2tr addresses only."
+    );
+
+    let prevouts: Vec<TxOut> = transaction
+        .input
+        .iter()
+        .map(|input| {
+            let utxo = own_utxos
+                .iter()
+                .find(|utxo| {
+                    utxo.outpoint.txid == input.previous_output.txid.into_inner().to_vec()
+                        && utxo.outpoint.vout == input.previous_output.vout
+                })
+                .expect("All the inputs must spend UTXOs of own_address.");
+            TxOut {
+                value: utxo.value,
+                script_pubkey: own_address.script_pubkey(),
+            }
+        })
+        .collect();
+
+    let sighashes = p2tr_key_spend_sighashes(&transaction, &prevouts);
+    for (input, sighash) in transaction.input.iter_mut().zip(sighashes) {
+        let signature = signer(key_name.clone(), derivation_path.clone(), sighash).await?;
+
+        // With the default sighash type, the witness is the bare signature.
+        input.script_sig = Script::new();
+        input.witness = Witness::from_vec(vec![signature]);
+    }
+
+    Ok(transaction)
+}
+
+// Returns the BIP-341 signature hashes of the inputs of `transaction`,
+// spent through the key path with the default sighash type, given the
+// outputs they spend.
+fn p2tr_key_spend_sighashes(transaction: &Transaction, prevouts: &[TxOut]) -> Vec<Vec<u8>> {
+    let mut sighash_cache = SighashCache::new(transaction);
+    (0..transaction.input.len())
+        .map(|index| {
+            sighash_cache
+                .taproot_key_spend_signature_hash(
+                    index,
+                    &Prevouts::All(prevouts),
+                    SchnorrSighashType::Default,
+                )
+                .expect("The prevouts must match the inputs.")
+                .into_inner()
+                .to_vec()
+        })
+        .collect()
+}
+
 fn sha256(data: &[u8]) -> Vec<u8> {
     let mut hasher = sha2::Sha256::new();
     hasher.update(data);
@@ -551,10 +2198,7 @@ fn public_key_to_p2pkh_address(network: BitcoinNetwork, public_key: &[u8]) -> St
     // SHA-256 & RIPEMD-160
     let result = ripemd160(&sha256(public_key));
 
-    let prefix = match network {
-        BitcoinNetwork::Testnet | BitcoinNetwork::Regtest => 0x6f,
-        BitcoinNetwork::Mainnet => 0x00,
-    };
+    let prefix = network_profile::profile(network).p2pkh_prefix;
     let mut data_with_prefix = vec![prefix];
     data_with_prefix.extend(result);
 
@@ -566,13 +2210,50 @@ fn public_key_to_p2pkh_address(network: BitcoinNetwork, public_key: &[u8]) -> St
     bs58::encode(full_address).into_string()
 }
 
+// Converts a public key to a P2WPKH address.
+fn public_key_to_p2wpkh_address(network: BitcoinNetwork, public_key: &[u8]) -> String {
+    let hrp = network_profile::profile(network).bech32_hrp;
+    // Witness version 0, then the hash of the public key.
+    let mut data = vec![bech32::u5::try_from_u8(0).unwrap()];
+    data.extend(ripemd160(&sha256(public_key)).to_base32());
+    bech32::encode(hrp, data, bech32::Variant::Bech32).unwrap()
+}
+
+// Returns the P2TR address whose output key is `public_key` (in the
+// compressed SEC1 format) tweaked without script paths.
+fn public_key_to_p2tr_address(network: BitcoinNetwork, public_key: &[u8]) -> Address {
+    let internal_key = XOnlyPublicKey::from_slice(&public_key[1..])
+        .expect("The public key must be a valid secp256k1 point.");
+    let network = network_profile::profile(network).bitcoin_network();
+    Address::p2tr(&Secp256k1::verification_only(), internal_key, None, network)
+}
+
 // A mock for rubber-stamping ECDSA signatures.
 async fn mock_signer(
     _key_name: String,
     _derivation_path: Vec<Vec<u8>>,
     _message_hash: Vec<u8>,
-) -> Vec<u8> {
-    vec![255; 64]
+) -> CallResult<Vec<u8>> {
+    Ok(vec![255; 64])
+}
+
+// A local stand-in for the Schnorr API that rubber-stamps BIP-340
+// signatures, which are always 64 bytes long.
+async fn mock_signer_schnorr(
+    _key_name: String,
+    _derivation_path: Vec<Vec<u8>>,
+    _message: Vec<u8>,
+) -> CallResult<Vec<u8>> {
+    Ok(vec![255; 64])
+}
+
+// A mock for rubber-stamping ECDSA signatures of P2WPKH inputs.
+async fn mock_signer_p2wpkh(
+    _key_name: String,
+    _derivation_path: DerivationPath,
+    _message_hash: [u8; 32],
+) -> Result<Vec<u8>, CallError> {
+    Ok(vec![255; 64])
 }
 
 // Converts a SEC1 ECDSA signature to the DER format.
@@ -608,3 +2289,437 @@ fn sec1_to_der(sec1_signature: Vec<u8>) -> Vec<u8> {
     .flatten()
     .collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_cdk::api::management_canister::bitcoin::Outpoint;
+    use std::cell::Cell;
+    use std::future::Future;
+    use std::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+
+    // Runs a future that never waits, like the ones of the mock signers.
+    fn block_on<F: Future>(future: F) -> F::Output {
+        fn raw_waker() -> RawWaker {
+            fn clone(_: *const ()) -> RawWaker {
+                raw_waker()
+            }
+            fn noop(_: *const ()) {}
+            static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+            RawWaker::new(std::ptr::null(), &VTABLE)
+        }
+        let waker = unsafe { Waker::from_raw(raw_waker()) };
+        let mut future = Box::pin(future);
+        match future.as_mut().poll(&mut Context::from_waker(&waker)) {
+            Poll::Ready(output) => output,
+            Poll::Pending => panic!("the future must not wait"),
+        }
+    }
+
+    #[test]
+    fn settle_fee_stops_when_dropping_the_change_shrinks_the_transaction() {
+        // 1_700 sats are left for fee and change. At 10 sat/vbyte, the 141
+        // vbytes with change cost 1_410 sats, which leaves 290 sats of
+        // change, below the 294 sats dust threshold. The 110 vbytes without
+        // change only cost 1_100 sats, which would leave change again.
+        let builds = Cell::new(0);
+        let (with_change, fee) = block_on(settle_fee(10_000, |total_fee| {
+            builds.set(builds.get() + 1);
+            assert!(builds.get() < 10, "the fee must settle");
+            let change = 1_700 - total_fee;
+            async move {
+                if change >= 294 {
+                    Ok((true, total_fee, 141))
+                } else {
+                    Ok((false, total_fee + change, 110))
+                }
+            }
+        }))
+        .unwrap();
+
+        assert!(!with_change);
+        assert_eq!(fee, 1_700);
+        assert_eq!(builds.get(), 2);
+    }
+
+    #[test]
+    fn settle_fee_raises_the_fee_until_it_pays_for_the_size() {
+        // Each fee increase pulls in another 68 vbytes input.
+        let (inputs, fee) = block_on(settle_fee(2_000, |total_fee| async move {
+            let inputs = 1 + total_fee / 300;
+            Ok((inputs, total_fee, 100 + 68 * inputs))
+        }))
+        .unwrap();
+
+        assert!(fee >= (100 + 68 * inputs) * 2);
+        assert_eq!((inputs, fee), (2, 472));
+    }
+
+    #[test]
+    fn settle_fee_forwards_build_errors() {
+        let result: Result<((), Satoshi), WalletError> = block_on(settle_fee(1_000, |_| async {
+            Err(WalletError::InsufficientFunds {
+                available: 1,
+                required: 2,
+            })
+        }));
+
+        assert!(matches!(result, Err(WalletError::InsufficientFunds { .. })));
+    }
+
+    #[test]
+    fn explicit_fee_rates_below_the_relay_minimum_are_rejected() {
+        let fee_options = |fee_rate| FeeOptions {
+            fee_rate: Some(fee_rate),
+            ..FeeOptions::default()
+        };
+
+        assert_eq!(
+            block_on(fee_per_byte(BitcoinNetwork::Mainnet, &fee_options(999))),
+            Err(WalletError::FeeRateTooLow {
+                fee_rate: 999,
+                min_fee_rate: MIN_RELAY_FEE_PER_BYTE,
+            })
+        );
+        assert_eq!(
+            block_on(fee_per_byte(BitcoinNetwork::Mainnet, &fee_options(1_000))),
+            Ok(1_000)
+        );
+    }
+
+    fn utxo(index: u8, value: Satoshi) -> Utxo {
+        Utxo {
+            outpoint: Outpoint {
+                txid: vec![index; 32],
+                vout: 0,
+            },
+            value,
+            height: index as u32,
+        }
+    }
+
+    // Newest first, as the bitcoin API lists them.
+    fn listed_utxos() -> Vec<Utxo> {
+        vec![utxo(3, 2_000), utxo(2, 5_000), utxo(1, 3_000)]
+    }
+
+    fn values(selected: Option<Vec<&Utxo>>) -> Option<Vec<Satoshi>> {
+        selected.map(|utxos| utxos.into_iter().map(|utxo| utxo.value).collect())
+    }
+
+    #[test]
+    fn selectors_leave_change_in_their_own_order() {
+        let utxos = listed_utxos();
+
+        assert_eq!(
+            values(OldestFirst.select(&utxos, 6_000)),
+            Some(vec![3_000, 5_000])
+        );
+        assert_eq!(
+            values(LargestFirst.select(&utxos, 6_000)),
+            Some(vec![5_000, 3_000])
+        );
+        assert_eq!(
+            values(Consolidation.select(&utxos, 6_000)),
+            Some(vec![2_000, 3_000, 5_000])
+        );
+    }
+
+    #[test]
+    fn selectors_spend_everything_on_an_exact_total() {
+        let utxos = listed_utxos();
+
+        for strategy in [
+            CoinSelection::OldestFirst,
+            CoinSelection::LargestFirst,
+            CoinSelection::BranchAndBound,
+            CoinSelection::Consolidation,
+        ] {
+            let selected = values(coin_selector(strategy, 0).select(&utxos, 10_000));
+            assert_eq!(
+                selected.map(|values| values.iter().sum::<Satoshi>()),
+                Some(10_000),
+                "{:?}",
+                strategy
+            );
+        }
+    }
+
+    #[test]
+    fn branch_and_bound_finds_a_selection_without_change() {
+        let utxos = listed_utxos();
+        let selector = BranchAndBound {
+            cost_of_change: 500,
+        };
+
+        // LargestFirst would take 5_000 + 3_000 and leave 1_000 of change.
+        assert_eq!(
+            values(selector.select(&utxos, 7_000)),
+            Some(vec![5_000, 2_000])
+        );
+        // Exceeding the target by less than the cost of change is a match too.
+        assert_eq!(
+            values(selector.select(&utxos, 6_800)),
+            Some(vec![5_000, 2_000])
+        );
+    }
+
+    #[test]
+    fn branch_and_bound_falls_back_to_largest_first() {
+        let utxos = listed_utxos();
+        let selector = BranchAndBound { cost_of_change: 0 };
+
+        assert_eq!(
+            values(selector.select(&utxos, 9_000)),
+            Some(vec![5_000, 3_000, 2_000])
+        );
+    }
+
+    #[test]
+    fn branch_and_bound_gives_up_on_large_wallets_after_its_tries() {
+        // Even values never sum up to an odd target, so the search runs
+        // out of tries before falling back.
+        let utxos: Vec<Utxo> = (0..50_000)
+            .map(|index| utxo((index % 256) as u8, 2 * (index % 1_000) + 2))
+            .collect();
+        let selector = BranchAndBound { cost_of_change: 0 };
+
+        let selected = values(selector.select(&utxos, 1_000_001)).unwrap();
+        assert!(selected.iter().sum::<Satoshi>() > 1_000_001);
+        assert_eq!(selected[0], 2_000);
+    }
+
+    #[test]
+    fn cost_of_change_pays_for_the_output_and_spending_it() {
+        assert_eq!(
+            cost_of_change(P2WPKH_OUTPUT_VSIZE, P2WPKH_INPUT_VSIZE, 10_000),
+            990
+        );
+        assert_eq!(
+            cost_of_change(P2TR_OUTPUT_VSIZE, P2TR_INPUT_VSIZE, 1_000),
+            101
+        );
+    }
+
+    #[test]
+    fn consolidation_skips_utxos_worth_less_than_spending_them() {
+        let utxos = vec![utxo(1, 700), utxo(2, 5_000), utxo(3, 680), utxo(4, 900)];
+
+        // At 10 sat/vbyte, spending an input costs 680 sats.
+        let inputs = consolidation_inputs(utxos.clone(), 2, 10_000);
+        assert_eq!(values(Some(inputs.iter().collect())), Some(vec![700, 900]));
+        let inputs = consolidation_inputs(utxos, 10, 10_000);
+        assert_eq!(
+            values(Some(inputs.iter().collect())),
+            Some(vec![700, 900, 5_000])
+        );
+    }
+
+    fn deposit(parent_vsize: u64, parent_fee: u64) -> AccelerateDepositArgs {
+        AccelerateDepositArgs {
+            ssi: String::new(),
+            outpoint: Outpoint {
+                txid: vec![1; 32],
+                vout: 0,
+            },
+            value: 100_000,
+            parent_vsize,
+            parent_fee,
+            target_fee_rate: 20_000,
+        }
+    }
+
+    #[test]
+    fn children_pay_for_the_fee_their_parent_is_missing() {
+        // At 20 sat/vbyte, the 250 vbytes of both need 5_000 sats, of which
+        // the parent pays 1_000.
+        assert_eq!(child_fee(&deposit(140, 1_000), 110), Ok(4_000));
+        // A parent paying the target already leaves the child its own share.
+        assert_eq!(child_fee(&deposit(140, 4_000), 110), Ok(2_200));
+    }
+
+    #[test]
+    fn children_pay_at_most_a_twentieth_of_the_deposit() {
+        assert_eq!(
+            child_fee(&deposit(1_000, 1_000), 110),
+            Err(WalletError::FeeAboveMax {
+                fee: 21_200,
+                max_fee: 5_000,
+            })
+        );
+    }
+
+    #[test]
+    fn parents_must_be_transactions_relays_accept() {
+        for (parent_vsize, parent_fee) in [(64, 1_000), (100_001, 200_000), (140, 139)] {
+            assert_eq!(
+                child_fee(&deposit(parent_vsize, parent_fee), 110),
+                Err(WalletError::InvalidParent {
+                    parent_vsize,
+                    parent_fee,
+                })
+            );
+        }
+    }
+
+    #[test]
+    fn selection_fails_without_enough_funds() {
+        let utxos = listed_utxos();
+
+        for strategy in [
+            CoinSelection::OldestFirst,
+            CoinSelection::LargestFirst,
+            CoinSelection::BranchAndBound,
+            CoinSelection::Consolidation,
+        ] {
+            let selector = coin_selector(strategy, 0);
+            assert_eq!(
+                values(selector.select(&utxos, 10_001)),
+                None,
+                "{:?}",
+                strategy
+            );
+        }
+        assert!(matches!(
+            select_utxos(&LargestFirst, &utxos, 10_001),
+            Err(WalletError::InsufficientFunds {
+                available: 10_000,
+                required: 10_001,
+            })
+        ));
+        assert!(matches!(
+            select_utxos(&LargestFirst, &[], 1),
+            Err(WalletError::InsufficientFunds {
+                available: 0,
+                required: 1,
+            })
+        ));
+    }
+
+    #[test]
+    fn p2tr_addresses_follow_bip_86() {
+        // The first receiving address of the BIP-86 test vector, whose
+        // internal key is given in the compressed format.
+        let public_key =
+            hex::decode("02cc8a4bc64d897bddc5fbc2f670f7a8ba0b386779106cf1223c6fc5d7cd6fc115")
+                .unwrap();
+
+        assert_eq!(
+            public_key_to_p2tr_address(BitcoinNetwork::Mainnet, &public_key).to_string(),
+            "bc1p5cyxnuxmeuwuvkwfem96lqzszd02n6xdcjrs20cac6yqjjwudpxqkedrcr"
+        );
+    }
+
+    #[test]
+    fn p2tr_sighashes_follow_bip_341() {
+        // The key path spending test vector of BIP-341, whose input 4 is
+        // signed with the default sighash type.
+        let transaction: Transaction = bitcoin::consensus::deserialize(
+            &hex::decode(
+                "02000000097de20cbff686da83a54981d2b9bab3586f4ca7e48f57f5b55963115f3b334e9c010000\
+                 000000000000d7b7cab57b1393ace2d064f4d4a2cb8af6def61273e127517d44759b6dafdd990000\
+                 000000fffffffff8e1f583384333689228c5d28eac13366be082dc57441760d957275419a4184200\
+                 00000000fffffffff0689180aa63b30cb162a73c6d2a38b7eeda2a83ece74310fda0843ad604853b\
+                 0100000000feffffffaa5202bdf6d8ccd2ee0f0202afbbb7461d9264a25e5bfd3c5a52ee1239e0ba\
+                 6c0000000000feffffff956149bdc66faa968eb2be2d2faa29718acbfe3941215893a2a3446d32ac\
+                 d050000000000000000000e664b9773b88c09c32cb70a2a3e4da0ced63b7ba3b22f848531bbb1d5d\
+                 5f4c94010000000000000000e9aa6b8e6c9de67619e6a3924ae25696bb7b694bb677a632a74ef7ea\
+                 dfd4eabf0000000000ffffffffa778eb6a263dc090464cd125c466b5a99667720b1c110468831d05\
+                 8aa1b82af10100000000ffffffff0200ca9a3b000000001976a91406afd46bcdfd22ef94ac122aa1\
+                 1f241244a37ecc88ac807840cb0000000020ac9a87f5594be208f8532db38cff670c450ed2fea8fc\
+                 defcc9a663f78bab962b0065cd1d",
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        let prevouts: Vec<TxOut> = [
+            (
+                "512053a1f6e454df1aa2776a2814a721372d6258050de330b3c6d10ee8f4e0dda343",
+                420_000_000,
+            ),
+            (
+                "5120147c9c57132f6e7ecddba9800bb0c4449251c92a1e60371ee77557b6620f3ea3",
+                462_000_000,
+            ),
+            (
+                "76a914751e76e8199196d454941c45d1b3a323f1433bd688ac",
+                294_000_000,
+            ),
+            (
+                "5120e4d810fd50586274face62b8a807eb9719cef49c04177cc6b76a9a4251d5450e",
+                504_000_000,
+            ),
+            (
+                "512091b64d5324723a985170e4dc5a0f84c041804f2cd12660fa5dec09fc21783605",
+                630_000_000,
+            ),
+            ("00147dd65592d0ab2fe0d0257d571abf032cd9db93dc", 378_000_000),
+            (
+                "512075169f4001aa68f15bbed28b218df1d0a62cbbcf1188c6665110c293c907b831",
+                672_000_000,
+            ),
+            (
+                "5120712447206d7a5238acc7ff53fbe94a3b64539ad291c7cdbc490b7577e4b17df5",
+                546_000_000,
+            ),
+            (
+                "512077e30a5522dd9f894c3f8b8bd4c4b2cf82ca7da8a3ea6a239655c39c050ab220",
+                588_000_000,
+            ),
+        ]
+        .iter()
+        .map(|(script_pubkey, value)| TxOut {
+            value: *value,
+            script_pubkey: Script::from(hex::decode(script_pubkey).unwrap()),
+        })
+        .collect();
+
+        let sighashes = p2tr_key_spend_sighashes(&transaction, &prevouts);
+        assert_eq!(
+            hex::encode(&sighashes[4]),
+            "4f900a0bae3f1446fd48490c2958b5a023228f01661cda3496a11da502a7f7ef"
+        );
+    }
+
+    #[test]
+    fn dust_limits_depend_on_the_output_type() {
+        let limits = network_profile::profile(BitcoinNetwork::Mainnet).dust_limits;
+        let cases = [
+            ("1BvBMSEYstWetqTFn5Au4m4GFg7xJaNVN2", 546),
+            ("3J98t1WpEZ73CNmQviecrnyiWrnqRhWNLy", 540),
+            ("bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4", 294),
+            (
+                "bc1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3qccfmv3",
+                330,
+            ),
+            (
+                "bc1p5cyxnuxmeuwuvkwfem96lqzszd02n6xdcjrs20cac6yqjjwudpxqkedrcr",
+                330,
+            ),
+        ];
+
+        for (address, threshold) in cases {
+            let parsed = Address::from_str(address).unwrap();
+            assert_eq!(
+                limits.of_address_type(parsed.address_type()),
+                threshold,
+                "{}",
+                address
+            );
+            let parsed = BitcoinAddress::parse(address, Network::Mainnet).unwrap();
+            assert_eq!(limits.of_bitcoin_address(&parsed), threshold, "{}", address);
+        }
+    }
+
+    #[test]
+    fn amounts_at_the_dust_threshold_are_accepted() {
+        assert!(check_dust(294, 294).is_ok());
+        assert!(matches!(
+            check_dust(293, 294),
+            Err(WalletError::AmountBelowDust {
+                amount: 293,
+                dust_threshold: 294,
+            })
+        ));
+    }
+}
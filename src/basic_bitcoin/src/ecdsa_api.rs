@@ -1,12 +1,17 @@
+use crate::network_profile;
 use crate::types::*;
 use candid::Principal;
-use ic_cdk::{api::call::call_with_payment, call};
+use ic_cdk::{
+    api::call::{call_with_payment, CallResult},
+    call,
+};
 
-// The fee for the `sign_with_ecdsa` endpoint using the test key.
-const SIGN_WITH_ECDSA_COST_CYCLES: u64 = 10_000_000_000;
-
-/// Returns the ECDSA public key of this canister at the given derivation path.
-pub async fn ecdsa_public_key(key_name: String, derivation_path: Vec<Vec<u8>>) -> Vec<u8> {
+/// Returns the ECDSA public key of this canister at the given derivation path,
+/// along with its chain code, or the rejection if the key does not exist.
+pub async fn ecdsa_public_key_reply(
+    key_name: String,
+    derivation_path: Vec<Vec<u8>>,
+) -> CallResult<ECDSAPublicKeyReply> {
     // Retrieve the public key of this canister at the given derivation path
     // from the ECDSA API.
     let res: Result<(ECDSAPublicKeyReply,), _> = call(
@@ -23,14 +28,16 @@ pub async fn ecdsa_public_key(key_name: String, derivation_path: Vec<Vec<u8>>) -
     )
     .await;
 
-    res.unwrap().0.public_key
+    res.map(|(reply,)| reply)
 }
 
+/// Signs `message_hash` with the key at the given derivation path, or
+/// returns the rejection of the management canister.
 pub async fn sign_with_ecdsa(
     key_name: String,
     derivation_path: Vec<Vec<u8>>,
     message_hash: Vec<u8>,
-) -> Vec<u8> {
+) -> CallResult<Vec<u8>> {
     let res: Result<(SignWithECDSAReply,), _> = call_with_payment(
         Principal::management_canister(),
         "sign_with_ecdsa",
@@ -42,9 +49,9 @@ pub async fn sign_with_ecdsa(
                 name: key_name,
             },
         },),
-        SIGN_WITH_ECDSA_COST_CYCLES,
+        network_profile::current().cycle_costs.sign_with_ecdsa,
     )
     .await;
 
-    res.unwrap().0.signature
+    res.map(|(reply,)| reply.signature)
 }
@@ -0,0 +1,193 @@
+//! A cache of the canister's public keys.
+//!
+//! Only the root key of each key name is fetched from the management
+//! canister. Keys at other derivation paths are derived from it locally,
+//! the way the IC derives them: non-hardened BIP-32 child derivation, where
+//! each element of the path is one index of arbitrary length.
+use crate::wallet_state::{
+    mutate_wallet_state, read_wallet_state, CachedPublicKey, KeyAlgorithm, PublicKeyId,
+};
+use crate::{ecdsa_api, schnorr_api};
+use bitcoin::hashes::{
+    hmac::{Hmac, HmacEngine},
+    sha512, Hash, HashEngine,
+};
+use bitcoin::secp256k1::{PublicKey, Secp256k1};
+
+/// Returns the ECDSA public key of this canister at the given derivation path.
+pub async fn ecdsa_public_key(key_name: String, derivation_path: Vec<Vec<u8>>) -> Vec<u8> {
+    public_key(KeyAlgorithm::EcdsaSecp256k1, key_name, derivation_path).await
+}
+
+/// Returns the BIP-340 public key of this canister at the given derivation
+/// path.
+pub async fn schnorr_public_key(key_name: String, derivation_path: Vec<Vec<u8>>) -> Vec<u8> {
+    public_key(KeyAlgorithm::SchnorrBip340Secp256k1, key_name, derivation_path).await
+}
+
+async fn public_key(
+    algorithm: KeyAlgorithm,
+    key_name: String,
+    derivation_path: Vec<Vec<u8>>,
+) -> Vec<u8> {
+    if let Some(public_key) = cached_public_key(algorithm, &key_name, &derivation_path) {
+        return public_key;
+    }
+
+    if let Err(err) = fetch_root_key(algorithm, &key_name).await {
+        ic_cdk::trap(&err);
+    }
+
+    cached_public_key(algorithm, &key_name, &derivation_path)
+        .expect("The root key must be cached.")
+}
+
+/// Fetches and caches the root key of `key_name`. Fails if the management
+/// canister does not know the key.
+pub async fn fetch_root_key(algorithm: KeyAlgorithm, key_name: &str) -> Result<(), String> {
+    let result = match algorithm {
+        KeyAlgorithm::EcdsaSecp256k1 => {
+            ecdsa_api::ecdsa_public_key_reply(key_name.to_string(), vec![])
+                .await
+                .map(|reply| CachedPublicKey {
+                    public_key: reply.public_key,
+                    chain_code: reply.chain_code,
+                })
+        }
+        KeyAlgorithm::SchnorrBip340Secp256k1 => {
+            schnorr_api::schnorr_public_key_reply(key_name.to_string(), vec![])
+                .await
+                .map(|reply| CachedPublicKey {
+                    public_key: reply.public_key,
+                    chain_code: reply.chain_code,
+                })
+        }
+    };
+    let root = result.map_err(|(code, message)| {
+        format!(
+            "failed to fetch the {:?} key {}: {:?} {}",
+            algorithm, key_name, code, message
+        )
+    })?;
+
+    mutate_wallet_state(|s| {
+        s.public_keys
+            .insert(PublicKeyId::root(algorithm, key_name), root)
+    });
+    Ok(())
+}
+
+/// Returns the public key at the given derivation path without any call,
+/// or `None` if the root key of `key_name` was never fetched.
+///
+/// Only root keys are cached: every caller has wallets of its own, so
+/// caching the derived keys would grow the cache without bound.
+pub fn cached_public_key(
+    algorithm: KeyAlgorithm,
+    key_name: &str,
+    derivation_path: &[Vec<u8>],
+) -> Option<Vec<u8>> {
+    let root = read_wallet_state(|s| s.public_keys.get(&PublicKeyId::root(algorithm, key_name)))?;
+    Some(derive_public_key(&root, derivation_path).public_key)
+}
+
+// Derives the key at `derivation_path` below `parent`, one step per element.
+fn derive_public_key(parent: &CachedPublicKey, derivation_path: &[Vec<u8>]) -> CachedPublicKey {
+    let secp = Secp256k1::verification_only();
+    let mut public_key =
+        PublicKey::from_slice(&parent.public_key).expect("The root key must be a valid point.");
+    let mut chain_code = parent.chain_code.clone();
+
+    for index in derivation_path {
+        let mut input = public_key.serialize().to_vec();
+        loop {
+            let mut engine = HmacEngine::<sha512::Hash>::new(&chain_code);
+            engine.input(&input);
+            engine.input(index);
+            let output = Hmac::<sha512::Hash>::from_engine(engine).into_inner();
+            let (offset, next_chain_code) = output.split_at(32);
+
+            let mut child = public_key;
+            if child.add_exp_assign(&secp, offset).is_ok() {
+                public_key = child;
+                chain_code = next_chain_code.to_vec();
+                break;
+            }
+            // The offset is out of range or the child is the point at
+            // infinity: try again with the next input, as SLIP-10 does.
+            input = [&[0x01][..], next_chain_code].concat();
+        }
+    }
+
+    CachedPublicKey {
+        public_key: public_key.serialize().to_vec(),
+        chain_code,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::util::bip32::ExtendedPubKey;
+    use std::str::FromStr;
+
+    // Extended public keys of test vector 1 of BIP-32, at m/0H/1/2H,
+    // m/0H/1/2H/2 and m/0H/1/2H/2/1000000000.
+    const XPUB_0H_1_2H: &str = "xpub6D4BDPcP2GT577Vvch3R8wDkScZWzQzMMUm3PWbmWvVJrZwQY4VUNgqFJPMM3No2dFDFGTsxxpG5uJh7n7epu4trkrX7x7DogT5Uv6fcLW5";
+    const XPUB_0H_1_2H_2: &str = "xpub6FHa3pjLCk84BayeJxFW2SP4XRrFd1JYnxeLeU8EqN3vDfZmbqBqaGJAyiLjTAwm6ZLRQUMv1ZACTj37sR62cfN7fe5JnJ7dh8zL4fiyLHV";
+    const XPUB_0H_1_2H_2_1000000000: &str = "xpub6H1LXWLaKsWFhvm6RVpEL9P4KfRZSW7abD2ttkWP3SSQvnyA8FSVqNTEcYFgJS2UaFcxupHiYkro49S8yGasTvXEYBVPamhGW6cFJodrTHy";
+
+    fn cached(xpub: &str) -> CachedPublicKey {
+        let xpub = ExtendedPubKey::from_str(xpub).unwrap();
+        CachedPublicKey {
+            public_key: xpub.public_key.serialize().to_vec(),
+            chain_code: xpub.chain_code.as_bytes().to_vec(),
+        }
+    }
+
+    fn assert_same_key(actual: &CachedPublicKey, expected: &CachedPublicKey) {
+        assert_eq!(
+            hex::encode(&actual.public_key),
+            hex::encode(&expected.public_key)
+        );
+        assert_eq!(
+            hex::encode(&actual.chain_code),
+            hex::encode(&expected.chain_code)
+        );
+    }
+
+    #[test]
+    fn the_empty_path_derives_the_parent() {
+        let parent = cached(XPUB_0H_1_2H);
+        assert_same_key(&derive_public_key(&parent, &[]), &parent);
+    }
+
+    // With 4-byte big-endian indices, the derivation is BIP-32's.
+    #[test]
+    fn derives_the_bip32_test_vectors() {
+        let parent = cached(XPUB_0H_1_2H);
+        assert_same_key(
+            &derive_public_key(&parent, &[2u32.to_be_bytes().to_vec()]),
+            &cached(XPUB_0H_1_2H_2),
+        );
+        assert_same_key(
+            &derive_public_key(
+                &parent,
+                &[
+                    2u32.to_be_bytes().to_vec(),
+                    1_000_000_000u32.to_be_bytes().to_vec(),
+                ],
+            ),
+            &cached(XPUB_0H_1_2H_2_1000000000),
+        );
+    }
+
+    #[test]
+    fn derivation_depends_on_every_byte_of_the_index() {
+        let parent = cached(XPUB_0H_1_2H);
+        let short = derive_public_key(&parent, &[vec![2]]);
+        let long = derive_public_key(&parent, &[2u32.to_be_bytes().to_vec()]);
+        assert_ne!(short.public_key, long.public_key);
+        assert_ne!(short.chain_code, long.chain_code);
+    }
+}
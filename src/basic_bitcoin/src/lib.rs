@@ -1,14 +1,24 @@
 mod bitcoin_api;
 mod bitcoin_wallet;
 mod ecdsa_api;
+mod key_cache;
+mod network_profile;
+mod retrieve_btc;
+mod schnorr_api;
+mod stable_memory;
+mod stable_state;
 mod types;
+mod wallet_state;
 
 use ic_cdk::{api::management_canister::bitcoin::{
-    BitcoinNetwork, GetUtxosResponse, MillisatoshiPerByte,
+    BitcoinNetwork, GetUtxosResponse, MillisatoshiPerByte, UtxoFilter,
 }, query};
 use ic_cdk_macros::{init, post_upgrade, pre_upgrade, update};
+use bitcoin_api::BitcoinApiError;
+use bitcoin_wallet::WalletError;
 use types::SendRequest;
 use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
 
 use candid::Principal;
 use ic_ckbtc_minter_syron::{
@@ -17,7 +27,7 @@ use ic_ckbtc_minter_syron::{
         init::MinterArg
     },
     management::get_exchange_rate,
-    state::{eventlog::Event, read_state},
+    state::{eventlog::Event, read_state, Network},
     storage::record_event,
     tasks::{schedule_now, TaskType},
     updates::{
@@ -29,42 +39,99 @@ use ic_ckbtc_minter_syron::{
 use icrc_ledger_types::icrc1::account::Subaccount;
 
 thread_local! {
-    // The bitcoin network to connect to.
-    //
-    // When developing locally this should be `Regtest`.
-    // When deploying to the IC this should be `Testnet`.
-    // `Mainnet` is currently unsupported.
+    // The derivation path of the canister's own wallet, which pays the
+    // `get_susd` transfers and is consolidated by controllers.
+    static DERIVATION_PATH: RefCell<Vec<Vec<u8>>> = RefCell::new(vec![]);
 
-    // @review (mainnet)
-    static NETWORK: Cell<BitcoinNetwork> = Cell::new(BitcoinNetwork::Testnet);
+    // The name of the threshold key, `ecdsa_key_name` of the minter state.
+    static KEY_NAME: RefCell<String> = RefCell::new(String::from(""));
 
-    // The derivation path to use for ECDSA secp256k1.
-    static DERIVATION_PATH: Vec<Vec<u8>> = vec![];
+    // The timer running the periodic UTXO consolidation, if enabled.
+    static CONSOLIDATION_TIMER: Cell<Option<ic_cdk_timers::TimerId>> = Cell::new(None);
 
-    // The ECDSA key name.
-    static KEY_NAME: RefCell<String> = RefCell::new(String::from(""));
+    // The timer broadcasting pending transactions again.
+    static REBROADCAST_TIMER: Cell<Option<ic_cdk_timers::TimerId>> = Cell::new(None);
+
+    // Why the key self-check after installation failed, for each key that
+    // failed it.
+    static KEY_SETUP_ERRORS: RefCell<BTreeMap<wallet_state::KeyAlgorithm, String>> =
+        RefCell::new(BTreeMap::new());
+
+    // When `get_susd` last paid each principal, in nanoseconds since the
+    // epoch.
+    static LAST_GET_SUSD: RefCell<BTreeMap<Principal, u64>> = RefCell::new(BTreeMap::new());
+}
+
+// Tags the derivation paths of the user wallets, keeping them apart from
+// the paths of the minter's vaults.
+const USER_WALLET_PATH_TAG: &[u8] = b"wallet";
+
+// Address queries fail with this message until the root public keys are
+// fetched after installation.
+const KEYS_NOT_LOADED: &str = "the public keys of the canister are not loaded yet, retry later";
+
+// Fails the call because the root public key of `algorithm` is missing, with
+// the reason the key self-check gave if it failed.
+fn keys_not_loaded(algorithm: wallet_state::KeyAlgorithm) -> ! {
+    match KEY_SETUP_ERRORS.with(|errors| errors.borrow().get(&algorithm).cloned()) {
+        Some(err) => ic_cdk::trap(&format!("the key self-check failed: {}", err)),
+        None => ic_cdk::trap(KEYS_NOT_LOADED),
+    }
+}
+
+// How long a principal waits between two `get_susd` calls.
+const GET_SUSD_INTERVAL_NANOS: u64 = 10 * 60 * 1_000_000_000;
+
+// How often the confirmations of sent transactions are checked.
+const TRANSACTION_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10 * 60);
+
+// How often the queue of `retrieve_btc` is checked for a due batch.
+const RETRIEVE_BTC_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
+// The shortest interval of the configurable tasks. Each round calls the
+// bitcoin API, which costs cycles, so the tasks must not run back to back.
+const MIN_TASK_INTERVAL_SECONDS: u64 = 10 * 60;
+
+// The bitcoin network the canister serves, i.e. `btc_network` of the minter
+// state. Its profile (see `network_profile`) fixes the key, address formats
+// and fees to use.
+//
+// When developing locally this should be `Regtest`.
+// When deploying to the IC this should be `Testnet` or `Mainnet`.
+fn network() -> BitcoinNetwork {
+    match read_state(|s| s.btc_network) {
+        Network::Mainnet => BitcoinNetwork::Mainnet,
+        Network::Testnet => BitcoinNetwork::Testnet,
+        Network::Regtest => BitcoinNetwork::Regtest,
+    }
+}
+
+// Traps unless the `network` argument of the canister names the same network
+// as `btc_network` of the minter.
+fn check_network(network: BitcoinNetwork, btc_network: Network) {
+    if network_profile::profile(network).minter_network() != btc_network {
+        ic_cdk::trap(&format!(
+            "the network argument {:?} does not match btc_network {:?} of the minter",
+            network, btc_network
+        ));
+    }
 }
 
 #[init]
 pub fn init(network: BitcoinNetwork, args: MinterArg) {
-    NETWORK.with(|n| n.set(network));
-
-    KEY_NAME.with(|key_name| {
-        key_name.replace(String::from(match network {
-            // For local development, we use a special test key with dfx.
-            BitcoinNetwork::Regtest => "dfx_test_key",
-            // On the IC we're using a test ECDSA key.
-            BitcoinNetwork::Mainnet | BitcoinNetwork::Testnet => "test_key_1",
-        }))
-    });
-    
     match args {
         MinterArg::Init(args) => {
+            check_network(network, args.btc_network);
+            validate_key_name(network, &args.ecdsa_key_name);
+            KEY_NAME.with(|key_name| key_name.replace(args.ecdsa_key_name.clone()));
+
             record_event(&Event::Init(args.clone()));
             lifecycle::init::init(args);
+            stable_memory::check_minter_memory();
             schedule_now(TaskType::ProcessLogic);
             schedule_now(TaskType::RefreshFeePercentiles);
             // schedule_now(TaskType::DistributeKytFee);
+            schedule_wallet_tasks();
 
             #[cfg(feature = "self_check")]
             ok_or_die(check_invariants())
@@ -76,96 +143,439 @@ pub fn init(network: BitcoinNetwork, args: MinterArg) {
 }
 
 /// Returns the balance of the given bitcoin address.
-#[update]
-pub async fn get_balance(address: String) -> u64 {
-    let network = NETWORK.with(|n| n.get());
-    bitcoin_api::get_balance(network, address).await
+/// If `min_confirmations` is set, only sufficiently confirmed UTXOs count.
+#[update(guard = "caller_is_not_anonymous")]
+pub async fn get_balance(
+    address: String,
+    min_confirmations: Option<u32>,
+) -> Result<u64, BitcoinApiError> {
+    let network = network();
+    bitcoin_api::get_balance(network, address, min_confirmations).await
+}
+
+/// Returns the UTXOs of the given bitcoin address, optionally filtered.
+#[update(guard = "caller_is_not_anonymous")]
+pub async fn get_utxos(
+    address: String,
+    filter: Option<UtxoFilter>,
+) -> Result<GetUtxosResponse, BitcoinApiError> {
+    let network = network();
+    bitcoin_api::get_utxos(network, address, filter).await
 }
 
-/// Returns the UTXOs of the given bitcoin address.
-#[update]
-pub async fn get_utxos(address: String) -> GetUtxosResponse {
-    let network = NETWORK.with(|n| n.get());
-    bitcoin_api::get_utxos(network, address).await
+/// Returns one page of UTXOs of the given bitcoin address.
+/// Pass the `next_page` of the previous response to fetch the following page.
+#[update(guard = "caller_is_not_anonymous")]
+pub async fn get_utxos_page(
+    address: String,
+    page: Option<Vec<u8>>,
+) -> Result<GetUtxosResponse, BitcoinApiError> {
+    let network = network();
+    bitcoin_api::get_utxos_page(network, address, page).await
 }
 
 /// Returns the 100 fee percentiles measured in millisatoshi/byte.
 /// Percentiles are computed from the last 10,000 transactions (if available).
-#[update]
-pub async fn get_current_fee_percentiles() -> Vec<MillisatoshiPerByte> {
-    let network = NETWORK.with(|n| n.get());
+#[update(guard = "caller_is_not_anonymous")]
+pub async fn get_current_fee_percentiles() -> Result<Vec<MillisatoshiPerByte>, BitcoinApiError> {
+    let network = network();
     bitcoin_api::get_current_fee_percentiles(network).await
 }
 
-/// Returns the P2PKH address of this canister at a specific derivation path.
-#[update]
-pub async fn get_p2pkh_address() -> String {
-    let derivation_path = DERIVATION_PATH.with(|d| d.clone());
+/// Returns the P2PKH address of the caller's wallet with the given account
+/// index, 0 if not set.
+#[query(guard = "caller_is_not_anonymous")]
+pub fn get_p2pkh_address(account: Option<u32>) -> String {
+    let derivation_path = caller_derivation_path(account);
     let key_name = KEY_NAME.with(|kn| kn.borrow().to_string());
-    let network = NETWORK.with(|n| n.get());
-    bitcoin_wallet::get_p2pkh_address(network, key_name, derivation_path).await
+    let network = network();
+    bitcoin_wallet::get_p2pkh_address(network, &key_name, &derivation_path)
+        .unwrap_or_else(|| keys_not_loaded(wallet_state::KeyAlgorithm::EcdsaSecp256k1))
 }
 
-#[update]
-pub async fn get_p2wpkh_address() -> String {
-    let derivation_path = DERIVATION_PATH.with(|d| d.clone());
+#[query(guard = "caller_is_not_anonymous")]
+pub fn get_p2wpkh_address(account: Option<u32>) -> String {
+    let derivation_path = caller_derivation_path(account);
     let key_name = KEY_NAME.with(|kn| kn.borrow().to_string());
-    bitcoin_wallet::get_p2wpkh_address(key_name, derivation_path).await
+    let network = network();
+    bitcoin_wallet::get_p2wpkh_address(network, &key_name, &derivation_path)
+        .unwrap_or_else(|| keys_not_loaded(wallet_state::KeyAlgorithm::EcdsaSecp256k1))
 }
 
-/// Send the given amount of bitcoin from this canister to the given address.
-/// Return the transaction ID.
+/// Returns the P2TR (key path only) address of the caller's wallet.
+#[query(guard = "caller_is_not_anonymous")]
+pub fn get_p2tr_address(account: Option<u32>) -> String {
+    let derivation_path = caller_derivation_path(account);
+    let key_name = KEY_NAME.with(|kn| kn.borrow().to_string());
+    let network = network();
+    bitcoin_wallet::get_p2tr_address(network, &key_name, &derivation_path)
+        .unwrap_or_else(|| keys_not_loaded(wallet_state::KeyAlgorithm::SchnorrBip340Secp256k1))
+}
+
+/// Send the given amount of bitcoin from the caller's wallet to the given
+/// address. Return the transaction ID.
 
 /// 1. Using P2PKH
-#[update]
-pub async fn send(request: types::SendRequest) -> String {
-    let derivation_path = DERIVATION_PATH.with(|d| d.clone());
-    let network = NETWORK.with(|n| n.get());
+#[update(guard = "caller_can_withdraw")]
+pub async fn send(request: types::SendRequest) -> Result<String, WalletError> {
+    let derivation_path = caller_derivation_path(request.account);
+    let network = network();
+    let key_name = KEY_NAME.with(|kn| kn.borrow().to_string());
+    let tx_id = bitcoin_wallet::send(network, derivation_path, key_name, &request).await?;
+
+    Ok(tx_id.to_string())
+}
+
+/// 2. Using P2WPKH
+#[update(guard = "caller_can_withdraw")]
+pub async fn transfer(request: types::SendRequest) -> Result<types::TransferResult, WalletError> {
+    let derivation_path = caller_derivation_path(request.account);
+    let network = network();
     let key_name = KEY_NAME.with(|kn| kn.borrow().to_string());
-    let tx_id = bitcoin_wallet::send(
+    bitcoin_wallet::send_p2wpkh(network, derivation_path, key_name, &request).await
+}
+
+/// 3. Using P2TR
+#[update(guard = "caller_can_withdraw")]
+pub async fn transfer_p2tr(request: types::SendRequest) -> Result<String, WalletError> {
+    let derivation_path = caller_derivation_path(request.account);
+    let network = network();
+    let key_name = KEY_NAME.with(|kn| kn.borrow().to_string());
+    let tx_id = bitcoin_wallet::send_p2tr(network, derivation_path, key_name, &request).await?;
+
+    Ok(tx_id.to_string())
+}
+
+/// Pays all the given outputs from the P2WPKH address of the caller's wallet
+/// in a single transaction that shares one change output and one fee.
+#[update(guard = "caller_can_withdraw")]
+pub async fn transfer_batch(
+    payouts: Vec<types::Payout>,
+    account: Option<u32>,
+    options: Option<types::BatchOptions>,
+) -> Result<types::BatchTransferResult, WalletError> {
+    let derivation_path = caller_derivation_path(account);
+    let network = network();
+    let key_name = KEY_NAME.with(|kn| kn.borrow().to_string());
+    bitcoin_wallet::send_p2wpkh_batch(
         network,
         derivation_path,
         key_name,
-        request.destination_address,
-        request.amount_in_satoshi,
+        &payouts,
+        &options.unwrap_or_default(),
     )
-    .await;
+    .await
+}
+
+/// Replaces a stuck P2WPKH transaction with one that pays `new_fee_rate`
+/// (millisatoshi/vbyte). Returns the replacement's id.
+///
+/// Callers can bump the transactions sent from their wallets, and operators
+/// those sent from the canister's own addresses, e.g. the batches paying
+/// `retrieve_btc` requests. The fee rate can be at most the highest current
+/// fee percentile.
+#[update(guard = "caller_can_withdraw")]
+pub async fn bump_fee(
+    txid: String,
+    new_fee_rate: MillisatoshiPerByte,
+) -> Result<String, WalletError> {
+    let caller = ic_cdk::caller();
+    let path = wallet_state::read_wallet_state(|s| {
+        s.transactions
+            .get(&txid)
+            .map(|record| record.derivation_path)
+    });
+    let authorized = match path {
+        Some(path) => match wallet_owner(&path) {
+            Some(owner) => owner == caller,
+            None => role_of(&caller).is_some(),
+        },
+        None => false,
+    };
+    if !authorized {
+        return Err(WalletError::UnknownTransaction(txid));
+    }
+    let network = network();
+    let key_name = KEY_NAME.with(|kn| kn.borrow().to_string());
+    bitcoin_wallet::bump_fee_p2wpkh(network, key_name, txid, new_fee_rate).await
+}
 
-    tx_id.to_string()
+/// Speeds up an unconfirmed deposit to an SSI vault with a child transaction
+/// that pays for both. Returns the child's id. Operators only.
+#[update(guard = "caller_is_operator")]
+pub async fn accelerate_deposit(args: types::AccelerateDepositArgs) -> Result<String, WalletError> {
+    let network = network();
+    let key_name = KEY_NAME.with(|kn| kn.borrow().to_string());
+    bitcoin_wallet::accelerate_deposit(network, key_name, args).await
 }
 
-/// 2. Using P2WPKH
-#[update]
-pub async fn transfer(request: types::SendRequest) -> String {
-    let derivation_path = DERIVATION_PATH.with(|d| d.clone());
-    let network = NETWORK.with(|n| n.get());
+/// Returns the inputs, outputs, size and fee of the transaction `transfer`
+/// would send for the same request, without signing or sending it.
+#[update(guard = "caller_is_not_anonymous")]
+pub async fn estimate_transfer(
+    request: types::SendRequest,
+) -> Result<types::TransferEstimate, WalletError> {
+    let derivation_path = caller_derivation_path(request.account);
+    let network = network();
+    let key_name = KEY_NAME.with(|kn| kn.borrow().to_string());
+    bitcoin_wallet::estimate_p2wpkh(network, derivation_path, key_name, &request).await
+}
+
+/// Merges up to `max_inputs` (at most 500) small UTXOs of the canister's P2WPKH
+/// address into one, unless the median fee rate is above `max_fee_rate`.
+#[update(guard = "caller_is_operator")]
+pub async fn consolidate_utxos(
+    max_inputs: u32,
+    max_fee_rate: MillisatoshiPerByte,
+) -> Result<types::ConsolidationResult, WalletError> {
+    let derivation_path = DERIVATION_PATH.with(|d| d.borrow().clone());
+    let network = network();
     let key_name = KEY_NAME.with(|kn| kn.borrow().to_string());
-    let tx_id = bitcoin_wallet::send_p2wpkh(
+    bitcoin_wallet::consolidate_p2wpkh(
         network,
         derivation_path,
         key_name,
-        request.destination_address,
-        request.amount_in_satoshi,
+        max_inputs as usize,
+        max_fee_rate,
     )
-    .await;
-    let res = std::str::from_utf8(&tx_id).unwrap().to_string();
-    res
+    .await
+}
+
+/// Enables the periodic consolidation task with the given settings, or
+/// disables it if `config` is not set.
+#[update(guard = "caller_is_controller")]
+fn set_consolidation_config(config: Option<types::ConsolidationConfig>) {
+    if let Some(config) = &config {
+        validate_task_interval(config.interval_seconds);
+    }
+    wallet_state::mutate_wallet_state(|s| s.consolidation_config = config.clone());
+    schedule_consolidation(config);
+}
+
+#[query]
+fn get_last_consolidation() -> Option<types::ConsolidationResult> {
+    wallet_state::read_wallet_state(|s| s.last_consolidation.clone())
+}
+
+/// Lets `operator` run the operational endpoints, such as
+/// `consolidate_utxos` and `accelerate_deposit`.
+#[update(guard = "caller_is_controller")]
+fn add_operator(operator: Principal) {
+    wallet_state::mutate_wallet_state(|s| s.operators.insert(operator));
+}
+
+#[update(guard = "caller_is_controller")]
+fn remove_operator(operator: Principal) {
+    wallet_state::mutate_wallet_state(|s| s.operators.remove(&operator));
+}
+
+/// Returns the role of `principal`, or of the caller if not set.
+#[query]
+fn get_role(principal: Option<Principal>) -> Option<types::Role> {
+    role_of(&principal.unwrap_or_else(ic_cdk::caller))
+}
+
+fn schedule_consolidation(config: Option<types::ConsolidationConfig>) {
+    if let Some(timer_id) = CONSOLIDATION_TIMER.with(|t| t.take()) {
+        ic_cdk_timers::clear_timer(timer_id);
+    }
+    let Some(config) = config else { return };
+
+    let interval = std::time::Duration::from_secs(config.interval_seconds);
+    let timer_id = ic_cdk_timers::set_timer_interval(interval, move || {
+        let config = config.clone();
+        ic_cdk::spawn(async move {
+            let derivation_path = DERIVATION_PATH.with(|d| d.borrow().clone());
+            let network = network();
+            let key_name = KEY_NAME.with(|kn| kn.borrow().to_string());
+            if let Err(err) = bitcoin_wallet::consolidate_p2wpkh(
+                network,
+                derivation_path,
+                key_name,
+                config.max_inputs as usize,
+                config.max_fee_rate,
+            )
+            .await
+            {
+                ic_cdk::println!("Skipped UTXO consolidation: {:?}", err);
+            }
+        })
+    });
+    CONSOLIDATION_TIMER.with(|t| t.set(Some(timer_id)));
+}
+
+/// Returns the status of a transaction sent by the wallet, if known.
+#[query]
+fn get_transaction_status(txid: String) -> Option<types::TransactionStatus> {
+    wallet_state::read_wallet_state(|s| s.transaction_status(&txid))
+}
+
+/// Returns the transactions sent by the wallet, newest first.
+#[query]
+fn list_transactions(offset: u64, limit: u64) -> Vec<types::TransactionRecord> {
+    wallet_state::read_wallet_state(|s| s.list_transactions(offset as usize, limit as usize))
+}
+
+/// Sets how long a transaction stays unconfirmed before it is broadcast
+/// again, and how many times it is before being flagged as stuck.
+#[update(guard = "caller_is_controller")]
+fn set_rebroadcast_config(config: types::RebroadcastConfig) {
+    validate_task_interval(config.interval_seconds);
+    wallet_state::mutate_wallet_state(|s| s.rebroadcast_config = config.clone());
+    schedule_rebroadcast(config);
+}
+
+/// Sets the most pages of UTXOs fetched per address, or restores the
+/// default if not set. Each page is a paid call to the bitcoin API.
+#[update(guard = "caller_is_controller")]
+fn set_max_utxo_pages(pages: Option<u32>) {
+    if pages == Some(0) {
+        ic_cdk::trap("at least one page of UTXOs must be fetched");
+    }
+    wallet_state::mutate_wallet_state(|s| s.max_utxo_pages = pages);
+}
+
+fn schedule_rebroadcast(config: types::RebroadcastConfig) {
+    if let Some(timer_id) = REBROADCAST_TIMER.with(|t| t.take()) {
+        ic_cdk_timers::clear_timer(timer_id);
+    }
+
+    // Checking at the rebroadcast interval delays each rebroadcast by at
+    // most one interval.
+    let interval = std::time::Duration::from_secs(config.interval_seconds);
+    let timer_id = ic_cdk_timers::set_timer_interval(interval, || {
+        ic_cdk::spawn(async {
+            let network = network();
+            let config = wallet_state::read_wallet_state(|s| s.rebroadcast_config.clone());
+            bitcoin_wallet::rebroadcast_pending_transactions(network, &config).await;
+        })
+    });
+    REBROADCAST_TIMER.with(|t| t.set(Some(timer_id)));
+}
+
+// Traps if a configurable task would run more often than every
+// `MIN_TASK_INTERVAL_SECONDS`.
+fn validate_task_interval(interval_seconds: u64) {
+    if interval_seconds < MIN_TASK_INTERVAL_SECONDS {
+        ic_cdk::trap(&format!(
+            "the interval must be at least {} seconds, got {}",
+            MIN_TASK_INTERVAL_SECONDS, interval_seconds
+        ));
+    }
+}
+
+// Traps unless `key_name` is the key of the profile of `network`.
+fn validate_key_name(network: BitcoinNetwork, key_name: &str) {
+    if let Err(err) = network_profile::profile(network).validate_key_name(key_name) {
+        ic_cdk::trap(&err);
+    }
+}
+
+// Checks that the configured key exists by fetching its root public keys
+// right after installation, which also lets addresses be derived in queries.
+// If it does not, address queries fail with the reason.
+fn schedule_key_setup() {
+    ic_cdk_timers::set_timer(std::time::Duration::ZERO, || {
+        ic_cdk::spawn(async {
+            let key_name = KEY_NAME.with(|kn| kn.borrow().to_string());
+            // Each key is checked on its own, so that a missing Schnorr key
+            // leaves the ECDSA addresses and the minter working.
+            for algorithm in [
+                wallet_state::KeyAlgorithm::EcdsaSecp256k1,
+                wallet_state::KeyAlgorithm::SchnorrBip340Secp256k1,
+            ] {
+                match key_cache::fetch_root_key(algorithm, &key_name).await {
+                    Ok(()) => {
+                        ic_cdk::println!("Found the {:?} key {}.", algorithm, key_name);
+                        KEY_SETUP_ERRORS.with(|e| e.borrow_mut().remove(&algorithm));
+                        if algorithm == wallet_state::KeyAlgorithm::EcdsaSecp256k1 {
+                            updates::get_btc_address::init_ecdsa_public_key().await;
+                        }
+                    }
+                    Err(err) => {
+                        ic_cdk::println!("Key self-check failed: {}", err);
+                        KEY_SETUP_ERRORS.with(|e| e.borrow_mut().insert(algorithm, err));
+                    }
+                }
+            }
+        })
+    });
+}
+
+fn schedule_transaction_checks() {
+    ic_cdk_timers::set_timer_interval(TRANSACTION_CHECK_INTERVAL, || {
+        ic_cdk::spawn(async {
+            let network = network();
+            bitcoin_wallet::check_pending_transactions(network).await;
+        })
+    });
+}
+
+// Starts the periodic tasks of the wallet layer from its current state.
+fn schedule_wallet_tasks() {
+    schedule_transaction_checks();
+    schedule_retrieve_btc();
+    let (rebroadcast_config, consolidation_config) = wallet_state::read_wallet_state(|s| {
+        (s.rebroadcast_config.clone(), s.consolidation_config.clone())
+    });
+    schedule_rebroadcast(rebroadcast_config);
+    schedule_consolidation(consolidation_config);
+    schedule_key_setup();
+}
+
+fn schedule_retrieve_btc() {
+    ic_cdk_timers::set_timer_interval(RETRIEVE_BTC_CHECK_INTERVAL, || {
+        ic_cdk::spawn(async {
+            let derivation_path = DERIVATION_PATH.with(|d| d.borrow().clone());
+            let network = network();
+            let key_name = KEY_NAME.with(|kn| kn.borrow().to_string());
+            retrieve_btc::process_queue(network, derivation_path, key_name).await;
+        })
+    });
 }
 
 #[pre_upgrade]
 fn pre_upgrade() {
-    let network = NETWORK.with(|n| n.get());
-    ic_cdk::storage::stable_save((network,)).expect("Saving network to stable store must succeed.");
+    // The minter state needs no saving: it is rebuilt from the event log.
+    stable_state::save(&stable_state::PersistedState {
+        key_name: KEY_NAME.with(|kn| kn.borrow().clone()),
+        derivation_path: DERIVATION_PATH.with(|d| d.borrow().clone()),
+        wallet: wallet_state::read_wallet_state(|s| s.heap_state()),
+    });
 }
 
 #[post_upgrade]
-fn post_upgrade(minter_arg: MinterArg) {
-    let network = ic_cdk::storage::stable_restore::<(BitcoinNetwork,)>()
-        .expect("Failed to read network from stable memory.")
-        .0;
+fn post_upgrade(network: BitcoinNetwork, minter_arg: MinterArg) {
+    // Must run before the minter opens its memory, which the `stable_save`
+    // of older versions left unreadable.
+    let saved = stable_state::restore();
+
+    let upgrade_args = match minter_arg {
+        MinterArg::Upgrade(args) => args,
+        MinterArg::Init(_) => ic_cdk::trap("expected UpgradeArgs got InitArgs"),
+    };
+    // Records the upgrade arguments as an event and replays the event log.
+    lifecycle::upgrade::post_upgrade(upgrade_args);
+    check_network(network, read_state(|s| s.btc_network));
+    stable_memory::check_minter_memory();
+
+    match saved {
+        Some(saved) => {
+            KEY_NAME.with(|kn| kn.replace(saved.key_name));
+            DERIVATION_PATH.with(|d| d.replace(saved.derivation_path));
+            wallet_state::mutate_wallet_state(|s| s.restore_heap_state(saved.wallet));
+        }
+        // Older versions did not save the wallet layer, so it starts over
+        // with the key of the minter.
+        None => {
+            KEY_NAME.with(|kn| kn.replace(read_state(|s| s.ecdsa_key_name.clone())));
+        }
+    }
+    validate_key_name(network, &KEY_NAME.with(|kn| kn.borrow().clone()));
 
-    //@review 
-    init(network, minter_arg);
+    schedule_now(TaskType::ProcessLogic);
+    schedule_now(TaskType::RefreshFeePercentiles);
+    schedule_wallet_tasks();
 }
 
 // Tyron's stablecoin metaprotocol
@@ -177,47 +587,191 @@ fn post_upgrade(minter_arg: MinterArg) {
 //     }
 // }
 
+// Returns the derivation path of the caller's wallet with the given account
+// index, 0 if not set. Each principal can only sign with its own paths.
+fn caller_derivation_path(account: Option<u32>) -> Vec<Vec<u8>> {
+    vec![
+        USER_WALLET_PATH_TAG.to_vec(),
+        ic_cdk::caller().as_slice().to_vec(),
+        account.unwrap_or(0).to_be_bytes().to_vec(),
+    ]
+}
+
+// Returns the owner of the wallet at `path`, or `None` if it is one of the
+// canister's own addresses.
+fn wallet_owner(path: &[Vec<u8>]) -> Option<Principal> {
+    match path {
+        [tag, owner, _account] if tag.as_slice() == USER_WALLET_PATH_TAG => {
+            Some(Principal::from_slice(owner))
+        }
+        _ => None,
+    }
+}
+
+// The anonymous principal is shared by everyone, so it cannot own a wallet.
+fn caller_is_not_anonymous() -> Result<(), String> {
+    if ic_cdk::caller() == Principal::anonymous() {
+        Err("anonymous callers have no wallet".to_string())
+    } else {
+        Ok(())
+    }
+}
+
+// Returns the most privileged role of `principal`, if any.
+fn role_of(principal: &Principal) -> Option<types::Role> {
+    if *principal == ic_cdk::id() {
+        Some(types::Role::MinterInternal)
+    } else if ic_cdk::api::is_controller(principal) {
+        Some(types::Role::Controller)
+    } else if wallet_state::read_wallet_state(|s| s.operators.contains(principal)) {
+        Some(types::Role::Operator)
+    } else {
+        None
+    }
+}
+
+fn caller_is_controller() -> Result<(), String> {
+    if ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        Ok(())
+    } else {
+        Err("only controllers can call this method".to_string())
+    }
+}
+
+// Operators act for the canister; controllers and the canister itself can
+// do whatever they can.
+fn caller_is_operator() -> Result<(), String> {
+    match role_of(&ic_cdk::caller()) {
+        Some(_) => Ok(()),
+        None => Err("only operators and controllers can call this method".to_string()),
+    }
+}
+
+// Sending from a wallet is a withdrawal as far as the minter mode goes.
+fn caller_can_withdraw() -> Result<(), String> {
+    caller_is_not_anonymous()?;
+    read_state(|s| s.mode.is_withdrawal_available_for(&ic_cdk::caller()))
+}
+
+fn caller_can_deposit() -> Result<(), String> {
+    read_state(|s| s.mode.is_deposit_available_for(&ic_cdk::caller()))
+}
+
+// `get_susd` pays from the canister's own wallet, so its callers must be
+// known.
+fn caller_can_get_susd() -> Result<(), String> {
+    caller_is_not_anonymous()?;
+    caller_can_deposit()
+}
+
 fn check_postcondition<T>(t: T) -> T {
     #[cfg(feature = "self_check")]
     ok_or_die(check_invariants());
     t
 }
 
-#[update]
-async fn get_btc_address(args: GetBtcAddressArgs) -> String {
+#[query]
+fn get_btc_address(args: GetBtcAddressArgs) -> String {
     // check_anonymous_caller();
-    updates::get_btc_address::get_btc_address(args).await
+    bitcoin_wallet::get_vault_address(&args.ssi)
+        .unwrap_or_else(|| keys_not_loaded(wallet_state::KeyAlgorithm::EcdsaSecp256k1))
 }
 
-#[update]
+#[update(guard = "caller_can_deposit")]
 async fn update_balance(args: UpdateBalanceArgs) -> Result<Vec<UtxoStatus>, UpdateBalanceError> {
     // check_anonymous_caller();
     check_postcondition(updates::update_balance::update_balance(args).await)
 }
 
-#[update]
-async fn get_susd(args: UpdateBalanceArgs) -> String {
+/// Mints SU$D for the new deposits to the vault of `args.ssi` and pays the
+/// SSI a dust output from the canister's wallet. Each principal can be paid
+/// once every ten minutes; calls that fail do not count.
+#[update(guard = "caller_can_get_susd")]
+async fn get_susd(args: UpdateBalanceArgs) -> Result<String, WalletError> {
+    let caller = ic_cdk::caller();
+    let now = ic_cdk::api::time();
+    LAST_GET_SUSD.with(|last| {
+        let mut last = last.borrow_mut();
+        last.retain(|_, at| now < at.saturating_add(GET_SUSD_INTERVAL_NANOS));
+        match last.get(&caller) {
+            Some(at) => Err(WalletError::RateLimited {
+                retry_at: at.saturating_add(GET_SUSD_INTERVAL_NANOS),
+            }),
+            None => Ok(()),
+        }
+    })?;
+
     let destination_address = (&args.ssi).to_string();
 
     // @dev 1. Update Balance (the user's Vault MUST have BTC deposit confirmed)
-    let _ = check_postcondition(updates::update_balance::update_balance(args).await);
-    
+    let statuses = check_postcondition(updates::update_balance::update_balance(args).await)
+        .map_err(|err| WalletError::NothingMinted(format!("{:?}", err)))?;
+    if !statuses
+        .iter()
+        .any(|status| matches!(status, UtxoStatus::Minted { .. }))
+    {
+        return Err(WalletError::NothingMinted(format!("{:?}", statuses)));
+    }
+
     let req = SendRequest{
         destination_address,
-        amount_in_satoshi: 546
+        amount_in_satoshi: 546,
+        min_confirmations: None,
+        fee_rate: None,
+        fee_percentile: None,
+        max_fee: None,
+        coin_selection: None,
+        account: None,
     };
 
     // @dev 2. Transfer stablecoin from minter to user address
-    let tx_id = transfer(req).await;
-    tx_id
+    let derivation_path = DERIVATION_PATH.with(|d| d.borrow().clone());
+    let network = network();
+    let key_name = KEY_NAME.with(|kn| kn.borrow().to_string());
+    let result = bitcoin_wallet::send_p2wpkh(network, derivation_path, key_name, &req).await?;
+    LAST_GET_SUSD.with(|last| last.borrow_mut().insert(caller, ic_cdk::api::time()));
+    Ok(result.txid)
+}
+
+/// Burns `amount_susd` of the caller's SU$D, in the smallest unit of the
+/// ledger, and pays the BTC they are worth at the current exchange rate to
+/// `btc_destination`, out of the collateral of the vault of `ssi`.
+///
+/// `ssi` must be the P2TR address of the caller's wallet with the given
+/// account index, 0 if not set. The caller must first approve the canister
+/// to spend the SU$D on the SU$D ledger (ICRC-2). The payout is queued and
+/// sent with others; its progress is reported by `retrieve_btc_status`.
+#[update(guard = "caller_can_withdraw")]
+async fn retrieve_btc(
+    ssi: String,
+    amount_susd: u128,
+    btc_destination: String,
+    account: Option<u32>,
+) -> Result<types::RetrieveBtcOk, types::RetrieveBtcError> {
+    let network = network();
+    let key_name = KEY_NAME.with(|kn| kn.borrow().to_string());
+    let caller_ssi =
+        bitcoin_wallet::get_p2tr_address(network, &key_name, &caller_derivation_path(account))
+            .unwrap_or_else(|| keys_not_loaded(wallet_state::KeyAlgorithm::SchnorrBip340Secp256k1));
+    if ssi != caller_ssi {
+        return Err(types::RetrieveBtcError::SsiNotOwned);
+    }
+    retrieve_btc::retrieve_btc(network, ssi, amount_susd, btc_destination).await
 }
 
-#[update]
-async fn get_subaccount(ssi: String) -> Subaccount {
+/// Returns where the `retrieve_btc` request that burnt SU$D at
+/// `block_index` stands.
+#[query]
+fn retrieve_btc_status(block_index: u64) -> types::RetrieveBtcStatus {
+    retrieve_btc::retrieve_btc_status(block_index)
+}
+
+#[query]
+fn get_subaccount(ssi: String) -> Subaccount {
     compute_subaccount(1, &ssi)
 }
 
-#[update]
+#[update(guard = "caller_is_not_anonymous")]
 async fn get_xr() -> u64 {
     let xr = match get_exchange_rate().await {
         Ok(result) => result,
@@ -228,6 +782,19 @@ async fn get_xr() -> u64 {
     xr.unwrap().rate
 }
 
+/// Returns the settings the canister runs with.
+#[query]
+fn get_config() -> types::Config {
+    read_state(|s| types::Config {
+        network: network(),
+        ecdsa_key_name: KEY_NAME.with(|kn| kn.borrow().clone()),
+        ledger_id: s.ledger_id.get().0,
+        susd_id: s.susd_id.get().0,
+        xrc_id: s.xrc_id.get().0,
+        max_utxo_pages: wallet_state::read_wallet_state(|s| s.max_utxo_pages()) as u32,
+    })
+}
+
 #[query]
 fn get_minter_info() -> MinterInfo {
     read_state(|s| MinterInfo {
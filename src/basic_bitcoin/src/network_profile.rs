@@ -0,0 +1,185 @@
+//! The settings that depend on the bitcoin network the canister serves.
+use bitcoin::AddressType;
+use ic_cdk::api::management_canister::bitcoin::{BitcoinNetwork, MillisatoshiPerByte};
+use ic_ckbtc_minter_syron::{address::BitcoinAddress, state::Network};
+
+/// Dust limits of the standard output types, i.e. the smallest values that
+/// relays accept.
+#[derive(Clone, Copy, Debug)]
+pub struct DustLimits {
+    pub p2pkh: u64,
+    pub p2sh: u64,
+    pub p2wpkh: u64,
+    pub p2wsh: u64,
+    pub p2tr: u64,
+}
+
+impl DustLimits {
+    /// The limit of outputs of the given type. Unknown types get the highest
+    /// limit, that of P2PKH.
+    pub fn of_address_type(&self, address_type: Option<AddressType>) -> u64 {
+        match address_type {
+            Some(AddressType::P2sh) => self.p2sh,
+            Some(AddressType::P2wpkh) => self.p2wpkh,
+            Some(AddressType::P2wsh) => self.p2wsh,
+            Some(AddressType::P2tr) => self.p2tr,
+            _ => self.p2pkh,
+        }
+    }
+
+    /// The limit of outputs paying to `address`.
+    pub fn of_bitcoin_address(&self, address: &BitcoinAddress) -> u64 {
+        match address {
+            BitcoinAddress::P2pkh(_) => self.p2pkh,
+            BitcoinAddress::P2sh(_) => self.p2sh,
+            BitcoinAddress::P2wpkhV0(_) => self.p2wpkh,
+            BitcoinAddress::P2wshV0(_) => self.p2wsh,
+            BitcoinAddress::P2tr(_) => self.p2tr,
+        }
+    }
+}
+
+/// The cycles attached to the calls to the management canister.
+#[derive(Clone, Copy, Debug)]
+pub struct CycleCosts {
+    pub get_balance: u64,
+    pub get_utxos: u64,
+    pub get_current_fee_percentiles: u64,
+    pub send_transaction_base: u64,
+    pub send_transaction_per_byte: u64,
+    pub sign_with_ecdsa: u64,
+    pub sign_with_schnorr: u64,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct NetworkProfile {
+    pub network: BitcoinNetwork,
+    /// The only threshold key the canister accepts on this network.
+    pub key_name: &'static str,
+    /// The human-readable part of the segwit addresses.
+    pub bech32_hrp: &'static str,
+    /// The version byte of P2PKH addresses.
+    pub p2pkh_prefix: u8,
+    /// The version byte of P2SH addresses.
+    pub p2sh_prefix: u8,
+    pub dust_limits: DustLimits,
+    /// The confirmations spent UTXOs need if a request sets none.
+    pub default_min_confirmations: u32,
+    /// The fee rate in millisatoshi/vbyte used when the network reports no
+    /// fee percentiles.
+    pub fallback_fee_rate: MillisatoshiPerByte,
+    pub cycle_costs: CycleCosts,
+}
+
+// The limits at the default dust relay fee of 3 sat/vbyte.
+const STANDARD_DUST_LIMITS: DustLimits = DustLimits {
+    p2pkh: 546,
+    p2sh: 540,
+    p2wpkh: 294,
+    p2wsh: 330,
+    p2tr: 330,
+};
+
+// The fees of the bitcoin API on mainnet, and of signing with `key_1` on the
+// 34-node subnet that holds it.
+const MAINNET_CYCLE_COSTS: CycleCosts = CycleCosts {
+    get_balance: 100_000_000,
+    get_utxos: 10_000_000_000,
+    get_current_fee_percentiles: 100_000_000,
+    send_transaction_base: 5_000_000_000,
+    send_transaction_per_byte: 20_000_000,
+    sign_with_ecdsa: 26_153_846_153,
+    sign_with_schnorr: 26_153_846_153,
+};
+
+const MAINNET: NetworkProfile = NetworkProfile {
+    network: BitcoinNetwork::Mainnet,
+    key_name: "key_1",
+    bech32_hrp: "bc",
+    p2pkh_prefix: 0x00,
+    p2sh_prefix: 0x05,
+    dust_limits: STANDARD_DUST_LIMITS,
+    default_min_confirmations: 6,
+    fallback_fee_rate: 10_000,
+    cycle_costs: MAINNET_CYCLE_COSTS,
+};
+
+const TESTNET: NetworkProfile = NetworkProfile {
+    network: BitcoinNetwork::Testnet,
+    key_name: "test_key_1",
+    bech32_hrp: "tb",
+    p2pkh_prefix: 0x6f,
+    p2sh_prefix: 0xc4,
+    dust_limits: STANDARD_DUST_LIMITS,
+    default_min_confirmations: 1,
+    fallback_fee_rate: 2_000,
+    cycle_costs: CycleCosts {
+        get_balance: 40_000_000,
+        get_utxos: 4_000_000_000,
+        get_current_fee_percentiles: 40_000_000,
+        send_transaction_base: 2_000_000_000,
+        send_transaction_per_byte: 8_000_000,
+        sign_with_ecdsa: 10_000_000_000,
+        sign_with_schnorr: 10_000_000_000,
+    },
+};
+
+// Cycles are free on the local replica, so it is paid as much as mainnet.
+const REGTEST: NetworkProfile = NetworkProfile {
+    network: BitcoinNetwork::Regtest,
+    key_name: "dfx_test_key",
+    bech32_hrp: "bcrt",
+    p2pkh_prefix: 0x6f,
+    p2sh_prefix: 0xc4,
+    dust_limits: STANDARD_DUST_LIMITS,
+    default_min_confirmations: 1,
+    // Regtest has no fee percentiles until it has non-coinbase
+    // transactions.
+    fallback_fee_rate: 2_000,
+    cycle_costs: MAINNET_CYCLE_COSTS,
+};
+
+/// Returns the profile of `network`.
+pub fn profile(network: BitcoinNetwork) -> &'static NetworkProfile {
+    match network {
+        BitcoinNetwork::Mainnet => &MAINNET,
+        BitcoinNetwork::Testnet => &TESTNET,
+        BitcoinNetwork::Regtest => &REGTEST,
+    }
+}
+
+/// Returns the profile of the network the canister serves.
+pub fn current() -> &'static NetworkProfile {
+    profile(crate::network())
+}
+
+impl NetworkProfile {
+    /// Checks that the canister can run on this network with `key_name`.
+    pub fn validate_key_name(&self, key_name: &str) -> Result<(), String> {
+        if key_name != self.key_name {
+            return Err(format!(
+                "{:?} requires the {} key, got {:?}",
+                self.network, self.key_name, key_name
+            ));
+        }
+        Ok(())
+    }
+
+    /// The network in the format of the minter.
+    pub fn minter_network(&self) -> Network {
+        match self.network {
+            BitcoinNetwork::Mainnet => Network::Mainnet,
+            BitcoinNetwork::Testnet => Network::Testnet,
+            BitcoinNetwork::Regtest => Network::Regtest,
+        }
+    }
+
+    /// The network in the format of the `bitcoin` crate.
+    pub fn bitcoin_network(&self) -> bitcoin::Network {
+        match self.network {
+            BitcoinNetwork::Mainnet => bitcoin::Network::Bitcoin,
+            BitcoinNetwork::Testnet => bitcoin::Network::Testnet,
+            BitcoinNetwork::Regtest => bitcoin::Network::Regtest,
+        }
+    }
+}
@@ -0,0 +1,526 @@
+//! Redemption of SU$D for BTC.
+//!
+//! `retrieve_btc` burns the caller's SU$D on its ledger and queues the BTC
+//! it is worth at the XRC rate. The queue is paid out in batches from the
+//! canister's P2WPKH wallet, once it is full or its oldest request waited
+//! `max_time_in_queue_nanos`.
+//!
+//! The BTC in the vaults does not move. Operators fund the canister's
+//! wallet, which floats the payouts, and `redeemed_sats` records how much of
+//! the collateral of each vault they released. Requests still unpaid after
+//! `RETRIEVE_BTC_EXPIRY_NANOS`, e.g. because the float ran out, are
+//! refunded: their SU$D is minted back to the caller and their satoshi count
+//! as collateral again.
+use crate::types::{
+    BatchOptions, Payout, RetrieveBtcError, RetrieveBtcOk, RetrieveBtcRequest, RetrieveBtcStatus,
+    TransactionStatus,
+};
+use crate::wallet_state::{mutate_wallet_state, read_wallet_state};
+use crate::{bitcoin_api, bitcoin_wallet};
+use candid::{CandidType, Deserialize, Nat, Principal};
+use ic_cdk::api::management_canister::bitcoin::BitcoinNetwork;
+use ic_ckbtc_minter_syron::{management::get_exchange_rate, state::read_state};
+use icrc_ledger_types::icrc1::account::Account;
+use icrc_ledger_types::icrc1::transfer::{TransferArg, TransferError};
+use icrc_ledger_types::icrc2::allowance::{Allowance, AllowanceArgs};
+use icrc_ledger_types::icrc2::transfer_from::{TransferFromArgs, TransferFromError};
+use std::cell::Cell;
+use std::convert::TryFrom;
+
+/// The most requests paid by one transaction.
+pub const MAX_REQUESTS_PER_BATCH: usize = 20;
+
+/// How long a request can wait unpaid before it is refunded.
+pub const RETRIEVE_BTC_EXPIRY_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+thread_local! {
+    // Set while a batch is being sent, so that the next round does not pay
+    // the same requests again.
+    static SENDING: Cell<bool> = Cell::new(false);
+}
+
+struct SendingGuard;
+
+impl SendingGuard {
+    fn new() -> Option<Self> {
+        if SENDING.with(|s| s.replace(true)) {
+            return None;
+        }
+        Some(Self)
+    }
+}
+
+// Also runs if the round traps after an await.
+impl Drop for SendingGuard {
+    fn drop(&mut self) {
+        SENDING.with(|s| s.set(false));
+    }
+}
+
+impl From<TransferFromError> for RetrieveBtcError {
+    fn from(err: TransferFromError) -> Self {
+        match err {
+            TransferFromError::InsufficientAllowance { allowance } => {
+                Self::InsufficientAllowance { allowance }
+            }
+            TransferFromError::InsufficientFunds { balance } => Self::InsufficientFunds { balance },
+            TransferFromError::TemporarilyUnavailable => {
+                Self::TemporarilyUnavailable("the SU$D ledger is unavailable".to_string())
+            }
+            err => Self::GenericError {
+                error_message: format!("{:?}", err),
+            },
+        }
+    }
+}
+
+/// Converts `amount_susd`, with `susd_decimals` decimals, to satoshi at
+/// `rate` USD per BTC, with `rate_decimals` decimals. Fractions of a satoshi
+/// are dropped. Returns `None` if the amount does not fit in 64 bits.
+pub fn susd_to_sats(
+    amount_susd: u128,
+    susd_decimals: u8,
+    rate: u64,
+    rate_decimals: u32,
+) -> Option<u64> {
+    // Bring the amount to 8 decimals, like BTC, first to keep it small.
+    let amount_usd = if susd_decimals >= BTC_DECIMALS {
+        amount_susd / 10u128.checked_pow((susd_decimals - BTC_DECIMALS) as u32)?
+    } else {
+        amount_susd.checked_mul(10u128.pow((BTC_DECIMALS - susd_decimals) as u32))?
+    };
+    let sats = amount_usd.checked_mul(10u128.checked_pow(rate_decimals)?)? / rate as u128;
+    u64::try_from(sats).ok()
+}
+
+const BTC_DECIMALS: u8 = 8;
+
+/// Burns `amount_susd` of the caller's SU$D, which the caller must have
+/// approved the canister to spend, and queues the payout of the BTC they
+/// are worth to `btc_destination`. The BTC is debited from the collateral
+/// of the vault of `ssi`, which the caller must own.
+pub async fn retrieve_btc(
+    network: BitcoinNetwork,
+    ssi: String,
+    amount_susd: u128,
+    btc_destination: String,
+) -> Result<RetrieveBtcOk, RetrieveBtcError> {
+    let caller = ic_cdk::caller();
+    let susd_id = read_state(|s| s.susd_id.get().0);
+    // Checked before the calls below, which the canister pays for, so that
+    // callers who cannot burn anything cannot spend its cycles.
+    let allowance = susd_allowance(susd_id, caller).await?;
+    if allowance < Nat::from(amount_susd) {
+        return Err(RetrieveBtcError::InsufficientAllowance { allowance });
+    }
+
+    let susd_decimals = query_susd_ledger::<u8>(susd_id, "icrc1_decimals").await?;
+
+    let exchange_rate = get_exchange_rate()
+        .await
+        .map_err(|err| format!("{:?}", err))
+        .and_then(|result| result.map_err(|err| format!("{:?}", err)))
+        .map_err(RetrieveBtcError::ExchangeRateUnavailable)?;
+    let rate = exchange_rate.rate;
+    let rate_decimals = exchange_rate.metadata.decimals;
+    if rate == 0 {
+        return Err(RetrieveBtcError::ExchangeRateUnavailable(
+            "the BTC/USD rate is zero".to_string(),
+        ));
+    }
+
+    let amount_sats =
+        susd_to_sats(amount_susd, susd_decimals, rate, rate_decimals).ok_or_else(|| {
+            RetrieveBtcError::GenericError {
+                error_message: "the amount of BTC does not fit in 64 bits".to_string(),
+            }
+        })?;
+    let min_amount = read_state(|s| s.retrieve_btc_min_amount);
+    if amount_sats < min_amount {
+        return Err(RetrieveBtcError::AmountTooLow(min_amount));
+    }
+    bitcoin_wallet::check_p2wpkh_payout(network, &btc_destination, amount_sats)
+        .map_err(RetrieveBtcError::InvalidPayout)?;
+
+    // Paying the minting account burns, so it must be known before taking
+    // the caller's SU$D.
+    let minting_account = query_susd_ledger::<Option<Account>>(susd_id, "icrc1_minting_account")
+        .await?
+        .ok_or_else(|| RetrieveBtcError::GenericError {
+            error_message: "the SU$D ledger has no minting account".to_string(),
+        })?;
+
+    let collateral = vault_collateral(network, &ssi).await?;
+    // Debited before the burn, so that concurrent requests cannot release
+    // the same collateral twice.
+    let debited = mutate_wallet_state(|s| {
+        let redeemed = s.redeemed_sats.entry(ssi.clone()).or_insert(0);
+        if collateral.saturating_sub(*redeemed) < amount_sats {
+            return Err(RetrieveBtcError::InsufficientCollateral {
+                available: collateral.saturating_sub(*redeemed),
+            });
+        }
+        *redeemed += amount_sats;
+        Ok(())
+    });
+    debited?;
+
+    let args = TransferFromArgs {
+        spender_subaccount: None,
+        from: Account {
+            owner: caller,
+            subaccount: None,
+        },
+        to: minting_account,
+        amount: Nat::from(amount_susd),
+        fee: None,
+        memo: None,
+        created_at_time: None,
+    };
+    let result: Result<Nat, RetrieveBtcError> =
+        ic_cdk::call::<_, (Result<Nat, TransferFromError>,)>(
+            susd_id,
+            "icrc2_transfer_from",
+            (args,),
+        )
+        .await
+        .map_err(|(code, message)| {
+            RetrieveBtcError::TemporarilyUnavailable(format!(
+                "the SU$D ledger rejected the call ({:?}): {}",
+                code, message
+            ))
+        })
+        .and_then(|(result,)| result.map_err(RetrieveBtcError::from));
+    let block_index = match result {
+        Ok(block_index) => block_index,
+        Err(err) => {
+            mutate_wallet_state(|s| {
+                if let Some(redeemed) = s.redeemed_sats.get_mut(&ssi) {
+                    *redeemed -= amount_sats;
+                }
+            });
+            return Err(err);
+        }
+    };
+    let block_index = u64::try_from(block_index.0).expect("block index must fit in 64 bits");
+
+    mutate_wallet_state(|s| {
+        s.retrieve_btc_requests.insert(
+            block_index,
+            RetrieveBtcRequest {
+                block_index,
+                ssi,
+                caller,
+                amount_susd,
+                amount_sats,
+                btc_destination,
+                rate,
+                rate_decimals,
+                received_at: ic_cdk::api::time(),
+                txid: None,
+                refunded: None,
+            },
+        )
+    });
+
+    Ok(RetrieveBtcOk {
+        block_index,
+        amount_sats,
+    })
+}
+
+// Calls a query of the SU$D ledger without arguments.
+async fn query_susd_ledger<T>(susd_id: Principal, method: &str) -> Result<T, RetrieveBtcError>
+where
+    T: CandidType + for<'de> Deserialize<'de>,
+{
+    let (reply,): (T,) = ic_cdk::call(susd_id, method, ())
+        .await
+        .map_err(|(code, message)| {
+            RetrieveBtcError::TemporarilyUnavailable(format!(
+                "the SU$D ledger rejected {} ({:?}): {}",
+                method, code, message
+            ))
+        })?;
+    Ok(reply)
+}
+
+// Returns how much SU$D `owner` approved the canister to spend.
+async fn susd_allowance(susd_id: Principal, owner: Principal) -> Result<Nat, RetrieveBtcError> {
+    let args = AllowanceArgs {
+        account: Account {
+            owner,
+            subaccount: None,
+        },
+        spender: Account {
+            owner: ic_cdk::id(),
+            subaccount: None,
+        },
+    };
+    let (allowance,): (Allowance,) = ic_cdk::call(susd_id, "icrc2_allowance", (args,))
+        .await
+        .map_err(|(code, message)| {
+            RetrieveBtcError::TemporarilyUnavailable(format!(
+                "the SU$D ledger rejected icrc2_allowance ({:?}): {}",
+                code, message
+            ))
+        })?;
+    Ok(allowance.allowance)
+}
+
+// Returns the confirmed BTC deposited to the vault of `ssi`, in satoshi.
+async fn vault_collateral(network: BitcoinNetwork, ssi: &str) -> Result<u64, RetrieveBtcError> {
+    let vault_address = bitcoin_wallet::get_vault_address(ssi).ok_or_else(|| {
+        RetrieveBtcError::TemporarilyUnavailable(
+            "the public keys of the canister are not loaded yet".to_string(),
+        )
+    })?;
+    let min_confirmations = read_state(|s| s.min_confirmations);
+    let listing = bitcoin_api::get_all_utxos(
+        network,
+        vault_address,
+        Some(min_confirmations),
+        read_wallet_state(|s| s.max_utxo_pages()),
+    )
+    .await
+    .map_err(|err| RetrieveBtcError::TemporarilyUnavailable(format!("{:?}", err)))?;
+    Ok(listing.utxos.iter().map(|utxo| utxo.value).sum())
+}
+
+/// Returns where the request that burnt SU$D at `block_index` stands.
+pub fn retrieve_btc_status(block_index: u64) -> RetrieveBtcStatus {
+    read_wallet_state(|s| {
+        let Some(request) = s.retrieve_btc_requests.get(&block_index) else {
+            return RetrieveBtcStatus::Unknown;
+        };
+        if let Some(block_index) = request.refunded {
+            return RetrieveBtcStatus::Refunded { block_index };
+        }
+        let Some(mut txid) = request.txid else {
+            return RetrieveBtcStatus::Pending;
+        };
+        // Fee bumps replace the transaction paying the request.
+        loop {
+            match s.transaction_status(&txid) {
+                Some(TransactionStatus::Replaced { replacement }) => txid = replacement,
+                Some(TransactionStatus::Confirmed { height }) => {
+                    return RetrieveBtcStatus::Confirmed { txid, height }
+                }
+                _ => return RetrieveBtcStatus::Submitted { txid },
+            }
+        }
+    })
+}
+
+/// Refunds the expired requests, then pays the oldest pending ones in one
+/// transaction from the wallet at `derivation_path`, if the queue is due.
+///
+/// `derivation_path` is the canister's own wallet, which floats the payouts
+/// (see the module documentation).
+pub async fn process_queue(
+    network: BitcoinNetwork,
+    derivation_path: Vec<Vec<u8>>,
+    key_name: String,
+) {
+    let Some(_guard) = SendingGuard::new() else {
+        return;
+    };
+    refund_expired_requests().await;
+
+    let batch: Vec<RetrieveBtcRequest> = read_wallet_state(|s| {
+        s.retrieve_btc_requests
+            .iter()
+            .map(|(_, request)| request)
+            .filter(|request| request.txid.is_none() && request.refunded.is_none())
+            .take(MAX_REQUESTS_PER_BATCH)
+            .collect()
+    });
+    let Some(oldest) = batch.iter().map(|request| request.received_at).min() else {
+        return;
+    };
+    let max_time_in_queue = read_state(|s| s.max_time_in_queue_nanos);
+    if batch.len() < MAX_REQUESTS_PER_BATCH
+        && ic_cdk::api::time() < oldest.saturating_add(max_time_in_queue)
+    {
+        return;
+    }
+
+    let payouts: Vec<Payout> = batch
+        .iter()
+        .map(|request| Payout {
+            destination_address: request.btc_destination.clone(),
+            amount_in_satoshi: request.amount_sats,
+        })
+        .collect();
+
+    let options = BatchOptions::default();
+    match bitcoin_wallet::send_p2wpkh_batch(network, derivation_path, key_name, &payouts, &options)
+        .await
+    {
+        Ok(sent) => mutate_wallet_state(|s| {
+            for mut request in batch {
+                request.txid = Some(sent.txid.clone());
+                s.retrieve_btc_requests.insert(request.block_index, request);
+            }
+        }),
+        // The requests stay queued for the next round.
+        Err(err) => ic_cdk::println!(
+            "Failed to pay {} retrieve_btc requests: {:?}",
+            batch.len(),
+            err
+        ),
+    }
+}
+
+// Whether `request` is still unpaid `RETRIEVE_BTC_EXPIRY_NANOS` after it
+// was accepted.
+fn is_expired(request: &RetrieveBtcRequest, now: u64) -> bool {
+    let expires_at = request
+        .received_at
+        .saturating_add(RETRIEVE_BTC_EXPIRY_NANOS);
+    request.txid.is_none() && request.refunded.is_none() && now >= expires_at
+}
+
+// Mints the SU$D of the expired requests back to their callers from the
+// minting account, which must be the canister's, and returns their satoshi
+// to the collateral of their SSI.
+async fn refund_expired_requests() {
+    let now = ic_cdk::api::time();
+    let expired: Vec<RetrieveBtcRequest> = read_wallet_state(|s| {
+        s.retrieve_btc_requests
+            .iter()
+            .map(|(_, request)| request)
+            .filter(|request| is_expired(request, now))
+            .collect()
+    });
+    if expired.is_empty() {
+        return;
+    }
+
+    let susd_id = read_state(|s| s.susd_id.get().0);
+    let minting_account =
+        match query_susd_ledger::<Option<Account>>(susd_id, "icrc1_minting_account").await {
+            Ok(Some(account)) if account.owner == ic_cdk::id() => account,
+            other => {
+                ic_cdk::println!(
+                    "Cannot refund {} expired retrieve_btc requests, the minting account is {:?}",
+                    expired.len(),
+                    other
+                );
+                return;
+            }
+        };
+
+    for mut request in expired {
+        let args = TransferArg {
+            from_subaccount: minting_account.subaccount,
+            to: Account {
+                owner: request.caller,
+                subaccount: None,
+            },
+            fee: None,
+            created_at_time: None,
+            memo: None,
+            amount: Nat::from(request.amount_susd),
+        };
+        let result =
+            ic_cdk::call::<_, (Result<Nat, TransferError>,)>(susd_id, "icrc1_transfer", (args,))
+                .await;
+        match result {
+            Ok((Ok(block_index),)) => mutate_wallet_state(|s| {
+                if let Some(redeemed) = s.redeemed_sats.get_mut(&request.ssi) {
+                    *redeemed = redeemed.saturating_sub(request.amount_sats);
+                }
+                request.refunded =
+                    Some(u64::try_from(block_index.0).expect("block index must fit in 64 bits"));
+                s.retrieve_btc_requests.insert(request.block_index, request);
+            }),
+            // Tried again in the next round.
+            Ok((Err(err),)) => ic_cdk::println!(
+                "Failed to refund the retrieve_btc request {}: {:?}",
+                request.block_index,
+                err
+            ),
+            Err((code, message)) => ic_cdk::println!(
+                "Failed to refund the retrieve_btc request {} ({:?}): {}",
+                request.block_index,
+                code,
+                message
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 50,000 USD per BTC, with the 9 decimals of the XRC.
+    const RATE: u64 = 50_000_000_000_000;
+    const RATE_DECIMALS: u32 = 9;
+
+    #[test]
+    fn scales_susd_by_its_decimals() {
+        // One SU$D, with 18 decimals, is worth 2,000 satoshi.
+        assert_eq!(
+            susd_to_sats(10u128.pow(18), 18, RATE, RATE_DECIMALS),
+            Some(2_000)
+        );
+        assert_eq!(
+            susd_to_sats(10u128.pow(8), 8, RATE, RATE_DECIMALS),
+            Some(2_000)
+        );
+        assert_eq!(susd_to_sats(100, 2, RATE, RATE_DECIMALS), Some(2_000));
+        // 50,000 SU$D buy one BTC.
+        assert_eq!(
+            susd_to_sats(50_000 * 10u128.pow(18), 18, RATE, RATE_DECIMALS),
+            Some(100_000_000)
+        );
+    }
+
+    #[test]
+    fn drops_fractions_of_a_satoshi() {
+        // 0.0001 SU$D is worth 0.2 satoshi.
+        assert_eq!(
+            susd_to_sats(10u128.pow(14), 18, RATE, RATE_DECIMALS),
+            Some(0)
+        );
+    }
+
+    #[test]
+    fn only_unpaid_requests_expire() {
+        let request = RetrieveBtcRequest {
+            block_index: 1,
+            ssi: String::new(),
+            caller: Principal::anonymous(),
+            amount_susd: 10u128.pow(18),
+            amount_sats: 2_000,
+            btc_destination: String::new(),
+            rate: RATE,
+            rate_decimals: RATE_DECIMALS,
+            received_at: 100,
+            txid: None,
+            refunded: None,
+        };
+        assert!(!is_expired(&request, 99 + RETRIEVE_BTC_EXPIRY_NANOS));
+        assert!(is_expired(&request, 100 + RETRIEVE_BTC_EXPIRY_NANOS));
+
+        let paid = RetrieveBtcRequest {
+            txid: Some("ab".repeat(32)),
+            ..request.clone()
+        };
+        assert!(!is_expired(&paid, u64::MAX));
+        let refunded = RetrieveBtcRequest {
+            refunded: Some(7),
+            ..request
+        };
+        assert!(!is_expired(&refunded, u64::MAX));
+    }
+
+    #[test]
+    fn rejects_amounts_beyond_64_bits() {
+        assert_eq!(susd_to_sats(u128::MAX, 18, RATE, RATE_DECIMALS), None);
+        assert_eq!(susd_to_sats(u128::MAX, 2, RATE, RATE_DECIMALS), None);
+        assert_eq!(susd_to_sats(10u128.pow(18), 18, 1, 40), None);
+    }
+}
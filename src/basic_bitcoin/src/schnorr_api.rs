@@ -0,0 +1,60 @@
+use crate::network_profile;
+use crate::types::*;
+use candid::Principal;
+use ic_cdk::{
+    api::call::{call_with_payment, CallResult},
+    call,
+};
+
+/// Returns the BIP-340 public key of this canister at the given derivation
+/// path, along with its chain code, or the rejection if the key does not
+/// exist.
+pub async fn schnorr_public_key_reply(
+    key_name: String,
+    derivation_path: Vec<Vec<u8>>,
+) -> CallResult<SchnorrPublicKeyReply> {
+    let res: Result<(SchnorrPublicKeyReply,), _> = call(
+        Principal::management_canister(),
+        "schnorr_public_key",
+        (SchnorrPublicKey {
+            canister_id: None,
+            derivation_path,
+            key_id: SchnorrKeyId {
+                algorithm: SchnorrAlgorithm::Bip340Secp256k1,
+                name: key_name,
+            },
+        },),
+    )
+    .await;
+
+    res.map(|(reply,)| reply)
+}
+
+/// Signs `message` with the key at the given derivation path, tweaked as the
+/// output key of a taproot address without script paths (BIP-86), or
+/// returns the rejection of the management canister.
+pub async fn sign_with_schnorr(
+    key_name: String,
+    derivation_path: Vec<Vec<u8>>,
+    message: Vec<u8>,
+) -> CallResult<Vec<u8>> {
+    let res: Result<(SignWithSchnorrReply,), _> = call_with_payment(
+        Principal::management_canister(),
+        "sign_with_schnorr",
+        (SignWithSchnorr {
+            message,
+            derivation_path,
+            key_id: SchnorrKeyId {
+                algorithm: SchnorrAlgorithm::Bip340Secp256k1,
+                name: key_name,
+            },
+            aux: Some(SignWithSchnorrAux::Bip341 {
+                merkle_root_hash: vec![],
+            }),
+        },),
+        network_profile::current().cycle_costs.sign_with_schnorr,
+    )
+    .await;
+
+    res.map(|(reply,)| reply.signature)
+}
@@ -0,0 +1,176 @@
+//! The stable memory holding the journals of the wallet layer.
+//!
+//! The minter keeps its event log in a memory manager private to its crate,
+//! laid over the whole stable memory from page 0. A second manager over the
+//! same memory would hand out buckets the minter's also hands out, whatever
+//! memory ids it uses, since neither knows the other's allocations. The
+//! wallet layer's manager is instead laid over the stable memory from
+//! `FIRST_PAGE` on, which leaves the first gigabyte to the minter. The
+//! stable memory is grown past it, and paid for, as soon as the wallet state
+//! is first accessed, since opening the manager writes its header there.
+//!
+//! The minter's manager only grows the stable memory when its buckets need
+//! more pages than there are, so it keeps writing below `FIRST_PAGE` until
+//! its buckets fill the gigabyte, which `check_minter_memory` looks out for.
+use crate::types::{RetrieveBtcRequest, TransactionRecord};
+use crate::wallet_state::{CachedPublicKey, PublicKeyId};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::storable::{Bound, Storable};
+use ic_stable_structures::{DefaultMemoryImpl, Memory};
+use std::borrow::Cow;
+use std::convert::TryInto;
+
+/// The first page of the stable memory used by the wallet layer.
+pub const FIRST_PAGE: u64 = 16 * 1024;
+
+/// The memory of `WalletState::transactions`.
+pub const TRANSACTIONS_MEMORY_ID: MemoryId = MemoryId::new(0);
+/// The memory of `WalletState::public_keys`.
+pub const PUBLIC_KEYS_MEMORY_ID: MemoryId = MemoryId::new(1);
+/// The memory of `WalletState::retrieve_btc_requests`.
+pub const RETRIEVE_BTC_REQUESTS_MEMORY_ID: MemoryId = MemoryId::new(2);
+/// The memory `stable_state` saves the heap state in on upgrades.
+pub const UPGRADE_STATE_MEMORY_ID: MemoryId = MemoryId::new(3);
+
+/// A memory of the wallet layer's memory manager.
+pub type StableMemory = VirtualMemory<OffsetMemory>;
+
+/// The pages of `memory` from `first_page` on.
+#[derive(Clone)]
+pub struct OffsetMemory {
+    memory: DefaultMemoryImpl,
+    first_page: u64,
+}
+
+impl OffsetMemory {
+    pub fn new(memory: DefaultMemoryImpl, first_page: u64) -> Self {
+        Self { memory, first_page }
+    }
+}
+
+impl Memory for OffsetMemory {
+    fn size(&self) -> u64 {
+        self.memory.size().saturating_sub(self.first_page)
+    }
+
+    fn grow(&self, pages: u64) -> i64 {
+        let size = self.size();
+        let missing = (self.first_page + size + pages).saturating_sub(self.memory.size());
+        if self.memory.grow(missing) < 0 {
+            return -1;
+        }
+        size as i64
+    }
+
+    fn read(&self, offset: u64, dst: &mut [u8]) {
+        self.memory
+            .read(self.first_page * WASM_PAGE_SIZE + offset, dst)
+    }
+
+    fn write(&self, offset: u64, src: &[u8]) {
+        self.memory
+            .write(self.first_page * WASM_PAGE_SIZE + offset, src)
+    }
+}
+
+const WASM_PAGE_SIZE: u64 = 64 * 1024;
+
+thread_local! {
+    // Allocations are only tracked by the manager that made them, so all the
+    // memories of the wallet layer must come from this one.
+    static MEMORY_MANAGER: MemoryManager<OffsetMemory> =
+        MemoryManager::init(OffsetMemory::new(DefaultMemoryImpl::default(), FIRST_PAGE));
+}
+
+/// Runs `f` with the memory manager of the wallet layer.
+pub fn with_memory_manager<R>(f: impl FnOnce(&MemoryManager<OffsetMemory>) -> R) -> R {
+    MEMORY_MANAGER.with(f)
+}
+
+/// Traps once the minter's memory manager reaches `FIRST_PAGE`, before it
+/// writes over the journals.
+pub fn check_minter_memory() {
+    let pages = manager_pages(&DefaultMemoryImpl::default());
+    if pages >= FIRST_PAGE {
+        ic_cdk::trap(&format!(
+            "the minter uses {} pages of stable memory, the wallet starts at page {}",
+            pages, FIRST_PAGE
+        ));
+    }
+}
+
+// Returns how many pages the memory manager at the start of `memory` uses,
+// from its header.
+fn manager_pages(memory: &impl Memory) -> u64 {
+    if memory.size() == 0 {
+        return 0;
+    }
+    let mut header = [0; 8];
+    memory.read(0, &mut header);
+    if &header[..3] != b"MGR" {
+        return 0;
+    }
+    let buckets = u16::from_le_bytes(header[4..6].try_into().unwrap()) as u64;
+    let bucket_size = u16::from_le_bytes(header[6..8].try_into().unwrap()) as u64;
+    // The first page holds the header.
+    1 + buckets * bucket_size
+}
+
+// The journals are stored candid-encoded, like the state saved on upgrades.
+macro_rules! candid_storable {
+    ($($type:ty),*) => {$(
+        impl Storable for $type {
+            fn to_bytes(&self) -> Cow<[u8]> {
+                Cow::Owned(Encode!(self).expect("failed to encode a stable value"))
+            }
+
+            fn from_bytes(bytes: Cow<[u8]>) -> Self {
+                Decode!(bytes.as_ref(), Self).expect("failed to decode a stable value")
+            }
+
+            const BOUND: Bound = Bound::Unbounded;
+        }
+    )*};
+}
+
+candid_storable!(
+    TransactionRecord,
+    PublicKeyId,
+    CachedPublicKey,
+    RetrieveBtcRequest
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_stable_structures::StableBTreeMap;
+
+    #[test]
+    fn the_wallet_manager_stays_clear_of_the_minter_manager() {
+        let memory = DefaultMemoryImpl::default();
+        let minter = MemoryManager::init(memory.clone());
+        let wallet = MemoryManager::init(OffsetMemory::new(memory.clone(), 256));
+
+        let mut events: StableBTreeMap<u64, u64, _> =
+            StableBTreeMap::init(minter.get(MemoryId::new(0)));
+        let mut journal: StableBTreeMap<u64, u64, _> =
+            StableBTreeMap::init(wallet.get(MemoryId::new(0)));
+        for i in 0..1_000 {
+            events.insert(i, i);
+            journal.insert(i, 2 * i);
+        }
+
+        assert!(memory.size() > 256);
+        assert_eq!(manager_pages(&memory), 1 + 128);
+        assert!((0..1_000).all(|i| events.get(&i) == Some(i) && journal.get(&i) == Some(2 * i)));
+    }
+
+    #[test]
+    fn memory_without_a_manager_uses_no_pages() {
+        let memory = DefaultMemoryImpl::default();
+        assert_eq!(manager_pages(&memory), 0);
+        memory.grow(1);
+        assert_eq!(manager_pages(&memory), 0);
+    }
+}
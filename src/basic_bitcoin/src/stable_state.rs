@@ -0,0 +1,180 @@
+//! Keeps the heap state of the wallet layer across upgrades.
+//!
+//! The journals are written to their own stable memory as they change (see
+//! `stable_memory`), so only the rest needs saving. `save` writes the encoded
+//! state to its own memory of the wallet layer, after a header holding
+//! `MAGIC` and the length of the state. Every upgrade writes over the state
+//! of the previous one, so the memory only grows when the state does.
+use crate::stable_memory::{self, UPGRADE_STATE_MEMORY_ID};
+use crate::wallet_state::HeapWalletState;
+use candid::{CandidType, Deserialize};
+use ic_stable_structures::{DefaultMemoryImpl, Memory};
+use std::convert::TryInto;
+
+const WASM_PAGE_SIZE: u64 = 64 * 1024;
+
+// Marks the memory as holding a saved wallet state.
+const MAGIC: &[u8; 8] = b"SYRONWLT";
+
+// `MAGIC` followed by the length of the encoded state.
+const HEADER_LEN: u64 = 16;
+
+// `stable_save` writes candid, which starts with this, from offset 0.
+const CANDID_MAGIC: &[u8; 4] = b"DIDL";
+
+/// What the wallet layer needs to resume after an upgrade.
+#[derive(CandidType, Deserialize)]
+pub struct PersistedState {
+    pub key_name: String,
+    /// The derivation path of the canister's own wallet.
+    pub derivation_path: Vec<Vec<u8>>,
+    pub wallet: HeapWalletState,
+}
+
+/// Writes `state` to the memory `UPGRADE_STATE_MEMORY_ID`.
+pub fn save(state: &PersistedState) {
+    stable_memory::with_memory_manager(|m| save_to(&m.get(UPGRADE_STATE_MEMORY_ID), state))
+}
+
+/// Reads the state written by `save`, if any.
+///
+/// Versions that saved the network with `stable_save((network,))` left it at
+/// the start of the stable memory, where the minter keeps its memory
+/// manager. The minter cannot open that memory, so upgrading from those
+/// versions traps here, before the minter touches it, and such canisters
+/// must be reinstalled instead.
+pub fn restore() -> Option<PersistedState> {
+    if saved_with_stable_save(&DefaultMemoryImpl::default()) {
+        ic_cdk::trap(
+            "the stable memory holds the network saved by stable_save, which this version \
+             cannot upgrade from: reinstall the canister",
+        );
+    }
+    stable_memory::with_memory_manager(|m| restore_from(&m.get(UPGRADE_STATE_MEMORY_ID)))
+}
+
+fn save_to(memory: &impl Memory, state: &PersistedState) {
+    let bytes = candid::encode_one(state).expect("failed to encode the wallet state");
+    let len = bytes.len() as u64;
+
+    let pages = (HEADER_LEN + len).div_ceil(WASM_PAGE_SIZE);
+    if pages > memory.size() && memory.grow(pages - memory.size()) < 0 {
+        panic!("failed to grow stable memory");
+    }
+
+    let mut header = MAGIC.to_vec();
+    header.extend_from_slice(&len.to_le_bytes());
+    memory.write(0, &header);
+    memory.write(HEADER_LEN, &bytes);
+}
+
+fn restore_from(memory: &impl Memory) -> Option<PersistedState> {
+    if memory.size() == 0 {
+        return None;
+    }
+
+    let mut header = [0; HEADER_LEN as usize];
+    memory.read(0, &mut header);
+    if &header[..8] != MAGIC {
+        return None;
+    }
+    let len = u64::from_le_bytes(header[8..].try_into().unwrap());
+
+    let mut bytes = vec![0; len as usize];
+    memory.read(HEADER_LEN, &mut bytes);
+    Some(candid::decode_one(&bytes).expect("failed to decode the wallet state"))
+}
+
+// Whether `memory` starts with the output of `stable_save`.
+fn saved_with_stable_save(memory: &impl Memory) -> bool {
+    if memory.size() == 0 {
+        return false;
+    }
+    let mut magic = [0; 4];
+    memory.read(0, &mut magic);
+    &magic == CANDID_MAGIC
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::RebroadcastConfig;
+    use crate::wallet_state::UtxoLock;
+    use candid::Principal;
+    use ic_cdk::api::management_canister::bitcoin::BitcoinNetwork;
+    use ic_stable_structures::VectorMemory;
+
+    fn persisted_state() -> PersistedState {
+        let mut wallet = HeapWalletState::default();
+        wallet.locked_utxos.insert(
+            (vec![1; 32], 0),
+            UtxoLock {
+                address: "bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq".to_string(),
+                txid: "ab".repeat(32),
+                locked_at: 42,
+            },
+        );
+        wallet.rebroadcast_config = RebroadcastConfig {
+            interval_seconds: 7_200,
+            ..RebroadcastConfig::default()
+        };
+        wallet.operators.insert(Principal::from_slice(&[7; 29]));
+        PersistedState {
+            key_name: "key_1".to_string(),
+            derivation_path: vec![b"wallet".to_vec(), vec![0, 0, 0, 1]],
+            wallet,
+        }
+    }
+
+    #[test]
+    fn restores_what_was_saved() {
+        let memory = VectorMemory::default();
+        save_to(&memory, &persisted_state());
+        let restored = restore_from(&memory).expect("the state must be restored");
+
+        let expected = persisted_state();
+        assert_eq!(restored.key_name, expected.key_name);
+        assert_eq!(restored.derivation_path, expected.derivation_path);
+        assert_eq!(
+            candid::encode_one(&restored.wallet).unwrap(),
+            candid::encode_one(&expected.wallet).unwrap()
+        );
+    }
+
+    #[test]
+    fn saving_again_reuses_the_same_pages() {
+        let memory = VectorMemory::default();
+        save_to(&memory, &persisted_state());
+        let pages = memory.size();
+
+        let mut state = persisted_state();
+        state.key_name = "test_key_1".to_string();
+        for _ in 0..10 {
+            save_to(&memory, &state);
+        }
+
+        assert_eq!(memory.size(), pages);
+        assert_eq!(restore_from(&memory).unwrap().key_name, "test_key_1");
+    }
+
+    #[test]
+    fn restores_nothing_from_memory_without_a_header() {
+        let memory = VectorMemory::default();
+        assert!(restore_from(&memory).is_none());
+        memory.grow(1);
+        assert!(restore_from(&memory).is_none());
+    }
+
+    #[test]
+    fn recognizes_the_output_of_stable_save() {
+        let memory = VectorMemory::default();
+        assert!(!saved_with_stable_save(&memory));
+        memory.grow(1);
+        memory.write(0, b"MGR\x01");
+        assert!(!saved_with_stable_save(&memory));
+
+        let network = candid::encode_args((BitcoinNetwork::Mainnet,)).unwrap();
+        memory.write(0, &network);
+        assert!(saved_with_stable_save(&memory));
+    }
+}
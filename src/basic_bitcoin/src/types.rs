@@ -1,10 +1,344 @@
-use candid::{CandidType, Deserialize, Principal};
+use candid::{CandidType, Deserialize, Nat, Principal};
+use ic_cdk::api::management_canister::bitcoin::{BitcoinNetwork, Outpoint, Utxo};
 use serde::Serialize;
 
-#[derive(CandidType, Deserialize)]
+use crate::bitcoin_wallet::{FeeOptions, WalletError};
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
 pub struct SendRequest {
     pub destination_address: String,
     pub amount_in_satoshi: u64,
+    /// The minimum number of confirmations the spent UTXOs must have.
+    pub min_confirmations: Option<u32>,
+    /// An explicit fee rate in millisatoshi/vbyte.
+    pub fee_rate: Option<u64>,
+    /// The percentile of recent fee rates to pay if no fee rate is given.
+    pub fee_percentile: Option<u8>,
+    /// The maximum total fee in satoshi the caller is willing to pay.
+    pub max_fee: Option<u64>,
+    /// How the UTXOs to spend are chosen, `OldestFirst` if not set.
+    pub coin_selection: Option<CoinSelection>,
+    /// The index of the caller's wallet to send from, 0 if not set.
+    pub account: Option<u32>,
+}
+
+/// The coin selection strategies of the wallet.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CoinSelection {
+    #[default]
+    OldestFirst,
+    LargestFirst,
+    /// Looks for inputs that need no change output.
+    BranchAndBound,
+    /// Spends the smallest UTXOs first.
+    Consolidation,
+}
+
+impl SendRequest {
+    pub fn fee_options(&self) -> FeeOptions {
+        FeeOptions {
+            fee_rate: self.fee_rate,
+            fee_percentile: self.fee_percentile,
+            max_fee: self.max_fee,
+        }
+    }
+}
+
+/// One of the outputs of a batch transfer.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Payout {
+    pub destination_address: String,
+    pub amount_in_satoshi: u64,
+}
+
+/// How a batch transfer is funded. Unset fields take the same defaults as
+/// in a `SendRequest`.
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct BatchOptions {
+    /// The minimum number of confirmations the spent UTXOs must have.
+    pub min_confirmations: Option<u32>,
+    /// An explicit fee rate in millisatoshi/vbyte.
+    pub fee_rate: Option<u64>,
+    /// The percentile of recent fee rates to pay if no fee rate is given.
+    pub fee_percentile: Option<u8>,
+    /// The maximum total fee in satoshi the caller is willing to pay.
+    pub max_fee: Option<u64>,
+    /// How the UTXOs to spend are chosen, `OldestFirst` if not set.
+    pub coin_selection: Option<CoinSelection>,
+}
+
+impl BatchOptions {
+    pub fn fee_options(&self) -> FeeOptions {
+        FeeOptions {
+            fee_rate: self.fee_rate,
+            fee_percentile: self.fee_percentile,
+            max_fee: self.max_fee,
+        }
+    }
+}
+
+/// A payout of a batch transfer and the output that pays it.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct PayoutResult {
+    pub destination_address: String,
+    pub amount_in_satoshi: u64,
+    pub vout: u32,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct BatchTransferResult {
+    pub txid: String,
+    pub fee: u64,
+    pub outputs: Vec<PayoutResult>,
+}
+
+/// An output of a transaction built by the wallet.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TransferOutput {
+    pub address: String,
+    pub value: u64,
+}
+
+/// A transaction sent by `transfer`.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TransferResult {
+    /// The transaction id, in hex as block explorers show it.
+    pub txid: String,
+    /// The witness transaction id, in the same format.
+    pub wtxid: String,
+    pub fee: u64,
+    pub vsize: u64,
+    pub inputs_used: Vec<Utxo>,
+    pub change: u64,
+}
+
+/// What a transfer would spend and cost, computed without signing or
+/// sending it.
+#[derive(CandidType, Deserialize, Debug)]
+pub struct TransferEstimate {
+    pub inputs: Vec<Utxo>,
+    pub outputs: Vec<TransferOutput>,
+    pub vsize: u64,
+    pub fee: u64,
+    pub change: u64,
+}
+
+/// What a principal is allowed to do with the canister, from least to most
+/// privileged.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    /// Runs the operational endpoints, e.g. `consolidate_utxos`.
+    Operator,
+    /// A controller of the canister, which also manages operators.
+    Controller,
+    /// The canister itself, e.g. in its timers.
+    MinterInternal,
+}
+
+/// The call that made the wallet send a transaction.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum TransactionRequest {
+    Send(SendRequest),
+    Transfer(SendRequest),
+    TransferP2tr(SendRequest),
+    TransferBatch(Vec<Payout>),
+    Consolidation { max_inputs: u32 },
+    BumpFee { txid: String },
+    AccelerateDeposit(AccelerateDepositArgs),
+}
+
+/// A transaction sent by the wallet.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TransactionRecord {
+    pub txid: String,
+    pub request: TransactionRequest,
+    /// The principal whose call sent the transaction.
+    pub caller: Principal,
+    /// When the transaction was sent, in nanoseconds since the epoch.
+    pub timestamp: u64,
+    /// The canister address funding the transaction.
+    pub source_address: String,
+    /// The derivation path of the key that signed the inputs.
+    pub derivation_path: Vec<Vec<u8>>,
+    pub inputs: Vec<Utxo>,
+    pub outputs: Vec<TransferOutput>,
+    /// The index of the change output, if any.
+    pub change_vout: Option<u32>,
+    pub fee: u64,
+    /// The fee rate in millisatoshi/vbyte the fee was computed with.
+    pub fee_rate: u64,
+    /// The signed transaction as it was broadcast.
+    pub raw_transaction: Vec<u8>,
+    /// When the transaction was last broadcast, in nanoseconds since the
+    /// epoch.
+    pub last_broadcast: u64,
+    /// How many times the transaction was broadcast again.
+    pub rebroadcasts: u32,
+    /// Set once the transaction reached the rebroadcast limit or the
+    /// confirmation check cutoff without confirming. It then needs a fee
+    /// bump or a manual review.
+    pub stuck: bool,
+    /// The height of the block that includes the transaction, once seen.
+    /// At the latest, if only a transaction spending it was seen.
+    pub confirmed_height: Option<u32>,
+    /// The transaction that replaced this one through a fee bump.
+    pub replaced_by: Option<String>,
+}
+
+/// Where a transaction sent by the wallet stands.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum TransactionStatus {
+    /// Broadcast, but not seen in a block yet.
+    Pending,
+    /// Included in the block at `height`.
+    Confirmed { height: u32 },
+    /// Replaced through a fee bump.
+    Replaced { replacement: String },
+    /// Broadcast `rebroadcasts` more times without confirming.
+    Stuck { rebroadcasts: u32 },
+}
+
+/// Settings of the task that broadcasts pending transactions again, since
+/// they can drop out of the mempools unnoticed.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct RebroadcastConfig {
+    /// How long a transaction stays unconfirmed before it is sent again.
+    pub interval_seconds: u64,
+    /// How many times a transaction is sent again before it is flagged as
+    /// stuck.
+    pub max_rebroadcasts: u32,
+}
+
+impl Default for RebroadcastConfig {
+    fn default() -> Self {
+        Self {
+            interval_seconds: 60 * 60,
+            max_rebroadcasts: 6,
+        }
+    }
+}
+
+/// A transaction that merged UTXOs of the canister into one output.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ConsolidationResult {
+    pub txid: String,
+    pub inputs: u32,
+    pub consolidated: u64,
+    pub fee: u64,
+    pub timestamp: u64,
+}
+
+/// Settings of the periodic UTXO consolidation task.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ConsolidationConfig {
+    pub interval_seconds: u64,
+    pub max_inputs: u32,
+    /// The task skips rounds where the median fee rate is higher.
+    pub max_fee_rate: u64,
+}
+
+/// An unconfirmed deposit to an SSI vault to speed up with a child
+/// transaction (CPFP).
+///
+/// The bitcoin API does not list mempool outputs, so the caller provides
+/// the value of the output and the size and fee of its transaction.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct AccelerateDepositArgs {
+    /// The SSI whose vault (see `get_btc_address`) received the deposit.
+    pub ssi: String,
+    pub outpoint: Outpoint,
+    pub value: u64,
+    /// The virtual size of the deposit transaction.
+    pub parent_vsize: u64,
+    /// The fee the deposit transaction pays.
+    pub parent_fee: u64,
+    /// The fee rate in millisatoshi/vbyte the parent and child should pay
+    /// together.
+    pub target_fee_rate: u64,
+}
+
+/// A redemption of SU$D for BTC, by the block index of the SU$D burn.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct RetrieveBtcRequest {
+    pub block_index: u64,
+    /// The SSI whose collateral the BTC is released from.
+    pub ssi: String,
+    pub caller: Principal,
+    /// The SU$D burnt, in the smallest unit of the ledger.
+    pub amount_susd: u128,
+    pub amount_sats: u64,
+    pub btc_destination: String,
+    /// The BTC/USD rate of the XRC the amount was computed at, with
+    /// `rate_decimals` decimals.
+    pub rate: u64,
+    pub rate_decimals: u32,
+    /// When the request was accepted, in nanoseconds since the epoch.
+    pub received_at: u64,
+    /// The transaction paying the request, once sent.
+    pub txid: Option<String>,
+    /// The block of the SU$D ledger that minted the SU$D back to the
+    /// caller, if the request expired unpaid.
+    pub refunded: Option<u64>,
+}
+
+/// A request accepted by `retrieve_btc`.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct RetrieveBtcOk {
+    /// The index of the burn on the SU$D ledger, which identifies the
+    /// request.
+    pub block_index: u64,
+    pub amount_sats: u64,
+}
+
+/// The reasons `retrieve_btc` can reject a request. Nothing is burnt
+/// unless the request is accepted.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum RetrieveBtcError {
+    /// The SSI is not the P2TR address of the caller's wallet at the
+    /// given account.
+    SsiNotOwned,
+    /// The amount of BTC is below `retrieve_btc_min_amount`.
+    AmountTooLow(u64),
+    /// The BTC cannot be paid to the destination, e.g. it is dust.
+    InvalidPayout(WalletError),
+    /// The XRC did not return a BTC/USD rate.
+    ExchangeRateUnavailable(String),
+    /// The vault of the SSI has only `available` satoshi left to release.
+    InsufficientCollateral { available: u64 },
+    /// The caller did not approve the canister to burn enough SU$D.
+    InsufficientAllowance { allowance: Nat },
+    InsufficientFunds { balance: Nat },
+    /// The SU$D ledger could not be reached.
+    TemporarilyUnavailable(String),
+    GenericError { error_message: String },
+}
+
+/// Where a request of `retrieve_btc` stands.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum RetrieveBtcStatus {
+    /// No request burnt SU$D at this block index.
+    Unknown,
+    /// Waiting in the queue.
+    Pending,
+    /// Paid by `txid`, which is not in a block yet.
+    Submitted { txid: String },
+    /// Paid by `txid`, included in the block at `height`.
+    Confirmed { txid: String, height: u32 },
+    /// Expired unpaid, and the SU$D was minted back to the caller at
+    /// `block_index` of the SU$D ledger.
+    Refunded { block_index: u64 },
+}
+
+/// The settings the canister runs with.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Config {
+    pub network: BitcoinNetwork,
+    pub ecdsa_key_name: String,
+    pub ledger_id: Principal,
+    pub susd_id: Principal,
+    pub xrc_id: Principal,
+    /// The most pages of UTXOs fetched per address.
+    pub max_utxo_pages: u32,
 }
 
 #[derive(CandidType, Serialize, Deserialize, Debug)]
@@ -43,3 +377,48 @@ pub struct SignWithECDSA {
     pub derivation_path: Vec<Vec<u8>>,
     pub key_id: EcdsaKeyId,
 }
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct SchnorrPublicKeyReply {
+    pub public_key: Vec<u8>,
+    pub chain_code: Vec<u8>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone)]
+pub struct SchnorrKeyId {
+    pub algorithm: SchnorrAlgorithm,
+    pub name: String,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone)]
+pub enum SchnorrAlgorithm {
+    #[serde(rename = "bip340secp256k1")]
+    Bip340Secp256k1,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone)]
+pub struct SchnorrPublicKey {
+    pub canister_id: Option<Principal>,
+    pub derivation_path: Vec<Vec<u8>>,
+    pub key_id: SchnorrKeyId,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct SignWithSchnorrReply {
+    pub signature: Vec<u8>,
+}
+
+#[derive(CandidType, Serialize, Debug)]
+pub struct SignWithSchnorr {
+    pub message: Vec<u8>,
+    pub derivation_path: Vec<Vec<u8>>,
+    pub key_id: SchnorrKeyId,
+    pub aux: Option<SignWithSchnorrAux>,
+}
+
+#[derive(CandidType, Serialize, Debug)]
+pub enum SignWithSchnorrAux {
+    /// Signs with the key tweaked as a taproot output key (BIP-341).
+    #[serde(rename = "bip341")]
+    Bip341 { merkle_root_hash: Vec<u8> },
+}
@@ -0,0 +1,568 @@
+//! State of the wallet layer, kept next to (and persisted separately from)
+//! the minter state.
+use crate::bitcoin_api::DEFAULT_MAX_UTXO_PAGES;
+use crate::bitcoin_wallet::vec_to_txid;
+use crate::stable_memory::{
+    self, OffsetMemory, StableMemory, PUBLIC_KEYS_MEMORY_ID, RETRIEVE_BTC_REQUESTS_MEMORY_ID,
+    TRANSACTIONS_MEMORY_ID,
+};
+use crate::types::{
+    ConsolidationConfig, ConsolidationResult, RebroadcastConfig, RetrieveBtcRequest,
+    TransactionRecord, TransactionStatus,
+};
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::management_canister::bitcoin::{Outpoint, Utxo};
+use ic_stable_structures::memory_manager::MemoryManager;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// How long an outpoint stays locked for a transaction that was never
+/// recorded, i.e. whose broadcast did not complete.
+pub const UNRECORDED_LOCK_EXPIRY_NANOS: u64 = 60 * 60 * 1_000_000_000;
+
+/// The most confirmations a request can require of the UTXOs it spends.
+///
+/// A listing that requires `n` confirmations shows the UTXO set of `n - 1`
+/// blocks ago, where outpoints spent since are still unspent. Locks last
+/// until their spending transaction is this deep, so that no listing shows
+/// a locked outpoint again.
+pub const MAX_MIN_CONFIRMATIONS: u32 = 144;
+
+/// How long after it was sent a transaction is still looked for in the UTXO
+/// sets. Older ones are flagged as stuck.
+pub const CONFIRMATION_CHECK_CUTOFF_NANOS: u64 = 14 * 24 * 60 * 60 * 1_000_000_000;
+
+/// How long after it was sent a confirmed or replaced transaction is kept.
+pub const SETTLED_TRANSACTION_RETENTION_NANOS: u64 = 90 * 24 * 60 * 60 * 1_000_000_000;
+
+/// The maximum number of transactions `list_transactions` returns at once.
+pub const MAX_TRANSACTIONS_PER_PAGE: usize = 100;
+
+/// An outpoint, as `(txid, vout)`.
+pub type OutpointKey = (Vec<u8>, u32);
+
+pub fn outpoint_key(outpoint: &Outpoint) -> OutpointKey {
+    (outpoint.txid.clone(), outpoint.vout)
+}
+
+/// An outpoint spent by a transaction that has not confirmed yet.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct UtxoLock {
+    /// The address holding the outpoint.
+    pub address: String,
+    /// The id of the transaction spending the outpoint.
+    pub txid: String,
+    pub locked_at: u64,
+}
+
+/// The signature schemes of the canister's keys.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum KeyAlgorithm {
+    EcdsaSecp256k1,
+    SchnorrBip340Secp256k1,
+}
+
+/// Identifies a public key of the canister.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PublicKeyId {
+    pub algorithm: KeyAlgorithm,
+    pub key_name: String,
+    pub derivation_path: Vec<Vec<u8>>,
+}
+
+impl PublicKeyId {
+    /// The key of the canister itself, from which all the others derive.
+    pub fn root(algorithm: KeyAlgorithm, key_name: &str) -> Self {
+        Self {
+            algorithm,
+            key_name: key_name.to_string(),
+            derivation_path: vec![],
+        }
+    }
+}
+
+/// A public key in the compressed SEC1 format, with its chain code.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct CachedPublicKey {
+    pub public_key: Vec<u8>,
+    pub chain_code: Vec<u8>,
+}
+
+/// The state of the wallet layer. The journals are kept in stable memory
+/// and written to directly, the rest is saved by `stable_state` on upgrades.
+pub struct WalletState {
+    /// Outpoints that coin selection must not spend again.
+    pub locked_utxos: BTreeMap<OutpointKey, UtxoLock>,
+    /// The transactions sent by the wallet, by txid.
+    pub transactions: StableBTreeMap<String, TransactionRecord, StableMemory>,
+    /// The settings of the periodic consolidation, if enabled.
+    pub consolidation_config: Option<ConsolidationConfig>,
+    pub last_consolidation: Option<ConsolidationResult>,
+    pub rebroadcast_config: RebroadcastConfig,
+    /// The root public keys fetched so far, from which the keys at other
+    /// derivation paths are derived.
+    pub public_keys: StableBTreeMap<PublicKeyId, CachedPublicKey, StableMemory>,
+    /// The principals allowed to run the operational endpoints.
+    pub operators: BTreeSet<Principal>,
+    /// The requests of `retrieve_btc`, by the block index of their burn.
+    pub retrieve_btc_requests: StableBTreeMap<u64, RetrieveBtcRequest, StableMemory>,
+    /// The satoshi `retrieve_btc` released from the collateral of each SSI.
+    pub redeemed_sats: BTreeMap<String, u64>,
+    /// The most pages of UTXOs fetched per address, if controllers set it.
+    pub max_utxo_pages: Option<u32>,
+}
+
+/// The part of `WalletState` kept on the heap.
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct HeapWalletState {
+    pub locked_utxos: BTreeMap<OutpointKey, UtxoLock>,
+    pub consolidation_config: Option<ConsolidationConfig>,
+    pub last_consolidation: Option<ConsolidationResult>,
+    pub rebroadcast_config: RebroadcastConfig,
+    pub operators: BTreeSet<Principal>,
+    pub redeemed_sats: BTreeMap<String, u64>,
+    pub max_utxo_pages: Option<u32>,
+}
+
+impl WalletState {
+    /// Opens the journals in the memories of `memory_manager`, with an empty
+    /// heap state.
+    pub fn init(memory_manager: &MemoryManager<OffsetMemory>) -> Self {
+        Self {
+            locked_utxos: BTreeMap::new(),
+            transactions: StableBTreeMap::init(memory_manager.get(TRANSACTIONS_MEMORY_ID)),
+            consolidation_config: None,
+            last_consolidation: None,
+            rebroadcast_config: RebroadcastConfig::default(),
+            public_keys: StableBTreeMap::init(memory_manager.get(PUBLIC_KEYS_MEMORY_ID)),
+            operators: BTreeSet::new(),
+            retrieve_btc_requests: StableBTreeMap::init(
+                memory_manager.get(RETRIEVE_BTC_REQUESTS_MEMORY_ID),
+            ),
+            redeemed_sats: BTreeMap::new(),
+            max_utxo_pages: None,
+        }
+    }
+
+    pub fn heap_state(&self) -> HeapWalletState {
+        HeapWalletState {
+            locked_utxos: self.locked_utxos.clone(),
+            consolidation_config: self.consolidation_config.clone(),
+            last_consolidation: self.last_consolidation.clone(),
+            rebroadcast_config: self.rebroadcast_config.clone(),
+            operators: self.operators.clone(),
+            redeemed_sats: self.redeemed_sats.clone(),
+            max_utxo_pages: self.max_utxo_pages,
+        }
+    }
+
+    pub fn restore_heap_state(&mut self, heap: HeapWalletState) {
+        self.locked_utxos = heap.locked_utxos;
+        self.consolidation_config = heap.consolidation_config;
+        self.last_consolidation = heap.last_consolidation;
+        self.rebroadcast_config = heap.rebroadcast_config;
+        self.operators = heap.operators;
+        self.redeemed_sats = heap.redeemed_sats;
+        self.max_utxo_pages = heap.max_utxo_pages;
+    }
+
+    /// The most pages of UTXOs to fetch per address.
+    pub fn max_utxo_pages(&self) -> usize {
+        self.max_utxo_pages
+            .map_or(DEFAULT_MAX_UTXO_PAGES, |pages| pages as usize)
+    }
+
+    pub fn is_locked(&self, outpoint: &Outpoint) -> bool {
+        self.locked_utxos.contains_key(&outpoint_key(outpoint))
+    }
+
+    /// Returns the UTXOs that are not locked by an in-flight transaction.
+    pub fn available_utxos(&self, utxos: &[Utxo]) -> Vec<Utxo> {
+        utxos
+            .iter()
+            .filter(|utxo| !self.is_locked(&utxo.outpoint))
+            .cloned()
+            .collect()
+    }
+
+    /// Locks all the given outpoints for `txid`, or none of them if any is
+    /// already locked. Returns whether the outpoints were locked.
+    pub fn try_lock(
+        &mut self,
+        outpoints: &[OutpointKey],
+        address: &str,
+        txid: &str,
+        now: u64,
+    ) -> bool {
+        if outpoints
+            .iter()
+            .any(|outpoint| self.locked_utxos.contains_key(outpoint))
+        {
+            return false;
+        }
+        for outpoint in outpoints {
+            self.locked_utxos.insert(
+                outpoint.clone(),
+                UtxoLock {
+                    address: address.to_string(),
+                    txid: txid.to_string(),
+                    locked_at: now,
+                },
+            );
+        }
+        true
+    }
+
+    pub fn unlock(&mut self, outpoints: &[OutpointKey]) {
+        for outpoint in outpoints {
+            self.locked_utxos.remove(outpoint);
+        }
+    }
+
+    /// Releases the locks whose spending transaction was replaced or is at
+    /// least `MAX_MIN_CONFIRMATIONS` deep below the tip at `tip_height`.
+    ///
+    /// Replacements take over the locks of the original, so a lock lasts as
+    /// long as the latest transaction spending its outpoint is not deep
+    /// enough.
+    pub fn release_locks(&mut self, tip_height: u32, now: u64) {
+        let transactions = &self.transactions;
+        self.locked_utxos
+            .retain(|_, lock| match transactions.get(&lock.txid) {
+                Some(record) => {
+                    let deep = record.confirmed_height.map_or(false, |height| {
+                        tip_height.saturating_sub(height) + 1 >= MAX_MIN_CONFIRMATIONS
+                    });
+                    !deep && record.replaced_by.is_none()
+                }
+                None => now < lock.locked_at.saturating_add(UNRECORDED_LOCK_EXPIRY_NANOS),
+            });
+    }
+
+    /// Returns the transactions whose confirmation is still looked for, and
+    /// flags as stuck the pending ones sent before the cutoff.
+    pub fn transactions_to_check(&mut self, now: u64) -> Vec<TransactionRecord> {
+        let mut to_check = vec![];
+        let pending: Vec<TransactionRecord> = self
+            .transactions
+            .iter()
+            .map(|(_, record)| record)
+            .filter(|record| {
+                record.confirmed_height.is_none() && record.replaced_by.is_none() && !record.stuck
+            })
+            .collect();
+        for mut record in pending {
+            let cutoff = record
+                .timestamp
+                .saturating_add(CONFIRMATION_CHECK_CUTOFF_NANOS);
+            if now >= cutoff {
+                record.stuck = true;
+                self.transactions.insert(record.txid.clone(), record);
+            } else {
+                to_check.push(record);
+            }
+        }
+        to_check
+    }
+
+    /// Records the heights of the pending transactions that have outputs in
+    /// `listed`, the heights of the listed UTXOs by txid.
+    ///
+    /// A transaction whose outputs were all spent is not listed anymore, so
+    /// a transaction is also confirmed once one spending it is, at the height
+    /// of the spending one at the latest.
+    pub fn record_confirmations(&mut self, listed: &BTreeMap<String, u32>) {
+        let mut pending: BTreeSet<String> = BTreeSet::new();
+        let mut spending: Vec<(String, u32)> = vec![];
+        for (txid, record) in self.transactions.iter() {
+            match record.confirmed_height {
+                Some(height) => spending.push((txid, height)),
+                None => {
+                    pending.insert(txid);
+                }
+            }
+        }
+
+        // The heights of the pending transactions found, by txid.
+        let mut found: BTreeMap<String, u32> = BTreeMap::new();
+        for txid in &pending {
+            if let Some(height) = listed.get(txid) {
+                found.insert(txid.clone(), *height);
+                spending.push((txid.clone(), *height));
+            }
+        }
+        while let Some((txid, height)) = spending.pop() {
+            let record = match self.transactions.get(&txid) {
+                Some(record) => record,
+                None => continue,
+            };
+            for input in &record.inputs {
+                let parent = vec_to_txid(input.outpoint.txid.clone()).to_string();
+                if !pending.contains(&parent) {
+                    continue;
+                }
+                match found.get(&parent) {
+                    Some(parent_height) if *parent_height <= height => {}
+                    _ => {
+                        found.insert(parent.clone(), height);
+                        spending.push((parent, height));
+                    }
+                }
+            }
+        }
+
+        for (txid, height) in found {
+            if let Some(mut record) = self.transactions.get(&txid) {
+                record.confirmed_height = Some(height);
+                self.transactions.insert(txid, record);
+            }
+        }
+    }
+
+    /// Forgets the confirmed and replaced transactions sent before the
+    /// retention period, and the `retrieve_btc` requests they paid.
+    pub fn prune_settled_transactions(&mut self, now: u64) {
+        let expired: Vec<String> = self
+            .transactions
+            .iter()
+            .filter(|(_, record)| {
+                let settled = record.confirmed_height.is_some() || record.replaced_by.is_some();
+                settled
+                    && now
+                        >= record
+                            .timestamp
+                            .saturating_add(SETTLED_TRANSACTION_RETENTION_NANOS)
+            })
+            .map(|(txid, _)| txid)
+            .collect();
+        for txid in expired {
+            self.transactions.remove(&txid);
+        }
+
+        let paid: Vec<u64> = self
+            .retrieve_btc_requests
+            .iter()
+            .filter(|(_, request)| match &request.txid {
+                Some(txid) => !self.transactions.contains_key(txid),
+                None => false,
+            })
+            .map(|(block_index, _)| block_index)
+            .collect();
+        for block_index in paid {
+            self.retrieve_btc_requests.remove(&block_index);
+        }
+    }
+
+    /// Returns where the given transaction stands, if the wallet sent it.
+    pub fn transaction_status(&self, txid: &str) -> Option<TransactionStatus> {
+        let record = self.transactions.get(&txid.to_string())?;
+        Some(match (&record.replaced_by, record.confirmed_height) {
+            (_, Some(height)) => TransactionStatus::Confirmed { height },
+            (Some(replacement), None) => TransactionStatus::Replaced {
+                replacement: replacement.clone(),
+            },
+            (None, None) if record.stuck => TransactionStatus::Stuck {
+                rebroadcasts: record.rebroadcasts,
+            },
+            (None, None) => TransactionStatus::Pending,
+        })
+    }
+
+    /// Returns up to `limit` transactions, newest first, skipping the
+    /// `offset` newest ones.
+    pub fn list_transactions(&self, offset: usize, limit: usize) -> Vec<TransactionRecord> {
+        let mut records: Vec<(u64, String)> = self
+            .transactions
+            .iter()
+            .map(|(txid, record)| (record.timestamp, txid))
+            .collect();
+        records.sort_by(|a, b| b.0.cmp(&a.0));
+        records
+            .into_iter()
+            .skip(offset)
+            .take(limit.min(MAX_TRANSACTIONS_PER_PAGE))
+            .filter_map(|(_, txid)| self.transactions.get(&txid))
+            .collect()
+    }
+}
+
+thread_local! {
+    static __STATE: RefCell<WalletState> =
+        RefCell::new(stable_memory::with_memory_manager(WalletState::init));
+}
+
+/// Reads the current wallet state.
+pub fn read_wallet_state<R>(f: impl FnOnce(&WalletState) -> R) -> R {
+    __STATE.with(|s| f(&s.borrow()))
+}
+
+/// Mutates the wallet state.
+pub fn mutate_wallet_state<R>(f: impl FnOnce(&mut WalletState) -> R) -> R {
+    __STATE.with(|s| f(&mut s.borrow_mut()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::TransactionRequest;
+    use ic_stable_structures::DefaultMemoryImpl;
+
+    const DAY_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+    // Txids made of a single repeated byte read the same in both byte
+    // orders.
+    fn txid(byte: u8) -> String {
+        format!("{:02x}", byte).repeat(32)
+    }
+
+    fn record(byte: u8, spent: &[u8], timestamp: u64) -> TransactionRecord {
+        TransactionRecord {
+            txid: txid(byte),
+            request: TransactionRequest::Consolidation { max_inputs: 0 },
+            caller: Principal::anonymous(),
+            timestamp,
+            source_address: String::new(),
+            derivation_path: vec![],
+            inputs: spent
+                .iter()
+                .map(|byte| Utxo {
+                    outpoint: Outpoint {
+                        txid: vec![*byte; 32],
+                        vout: 0,
+                    },
+                    value: 1_000,
+                    height: 0,
+                })
+                .collect(),
+            outputs: vec![],
+            change_vout: None,
+            fee: 0,
+            fee_rate: 0,
+            raw_transaction: vec![],
+            last_broadcast: timestamp,
+            rebroadcasts: 0,
+            stuck: false,
+            confirmed_height: None,
+            replaced_by: None,
+        }
+    }
+
+    fn state(records: Vec<TransactionRecord>) -> WalletState {
+        let memory = OffsetMemory::new(DefaultMemoryImpl::default(), 0);
+        let mut state = WalletState::init(&MemoryManager::init(memory));
+        for record in records {
+            state.transactions.insert(record.txid.clone(), record);
+        }
+        state
+    }
+
+    fn transaction(state: &WalletState, byte: u8) -> TransactionRecord {
+        state.transactions.get(&txid(byte)).unwrap()
+    }
+
+    fn height(state: &WalletState, byte: u8) -> Option<u32> {
+        transaction(state, byte).confirmed_height
+    }
+
+    #[test]
+    fn transactions_confirm_once_listed_or_spent_by_a_confirmed_one() {
+        // 2 spends the change of 1 and 3 spends the change of 2.
+        let mut state = state(vec![
+            record(1, &[], 0),
+            record(2, &[1], 0),
+            record(3, &[2], 0),
+        ]);
+
+        state.record_confirmations(&BTreeMap::new());
+        assert_eq!(height(&state, 1), None);
+
+        let listed: BTreeMap<String, u32> = vec![(txid(3), 120)].into_iter().collect();
+        state.record_confirmations(&listed);
+        assert_eq!(height(&state, 3), Some(120));
+        assert_eq!(height(&state, 2), Some(120));
+        assert_eq!(height(&state, 1), Some(120));
+    }
+
+    #[test]
+    fn transactions_past_the_cutoff_are_stuck_and_not_checked() {
+        let mut state = state(vec![record(1, &[], 0), record(2, &[], 10 * DAY_NANOS)]);
+
+        let checked: Vec<String> = state
+            .transactions_to_check(15 * DAY_NANOS)
+            .into_iter()
+            .map(|record| record.txid.clone())
+            .collect();
+        assert_eq!(checked, vec![txid(2)]);
+        assert!(transaction(&state, 1).stuck);
+        assert!(!transaction(&state, 2).stuck);
+    }
+
+    #[test]
+    fn locks_last_until_the_spend_is_deeper_than_any_listing_requires() {
+        let mut confirmed = record(1, &[], 0);
+        confirmed.confirmed_height = Some(100);
+        let mut replaced = record(2, &[], 0);
+        replaced.replaced_by = Some(txid(3));
+        let mut state = state(vec![confirmed, replaced, record(3, &[], 0)]);
+        for (outpoint, byte) in [(10, 1), (11, 2), (12, 3), (13, 4)] {
+            let outpoint = (vec![outpoint; 32], 0);
+            assert!(state.try_lock(&[outpoint], "address", &txid(byte), 0));
+        }
+        let locked = |state: &WalletState| -> Vec<u8> {
+            state.locked_utxos.keys().map(|(txid, _)| txid[0]).collect()
+        };
+
+        // The replaced spend releases at once, the confirmed one stays
+        // locked while a listing can still show its outpoint as unspent.
+        state.release_locks(100 + MAX_MIN_CONFIRMATIONS - 2, 0);
+        assert_eq!(locked(&state), vec![10, 12, 13]);
+
+        state.release_locks(100 + MAX_MIN_CONFIRMATIONS - 1, 0);
+        assert_eq!(locked(&state), vec![12, 13]);
+
+        // The lock of a transaction that was never recorded expires.
+        state.release_locks(0, UNRECORDED_LOCK_EXPIRY_NANOS);
+        assert_eq!(locked(&state), vec![12]);
+    }
+
+    #[test]
+    fn only_settled_transactions_past_the_retention_are_pruned() {
+        let mut confirmed = record(1, &[], 0);
+        confirmed.confirmed_height = Some(100);
+        let mut replaced = record(2, &[], 0);
+        replaced.replaced_by = Some(txid(4));
+        let mut recent = record(3, &[], 60 * DAY_NANOS);
+        recent.confirmed_height = Some(200);
+        let mut state = state(vec![confirmed, replaced, recent, record(4, &[], 0)]);
+
+        for (block_index, paid_by) in [(1, Some(txid(1))), (2, Some(txid(3))), (3, None)] {
+            state.retrieve_btc_requests.insert(
+                block_index,
+                RetrieveBtcRequest {
+                    block_index,
+                    ssi: String::new(),
+                    caller: Principal::anonymous(),
+                    amount_susd: 0,
+                    amount_sats: 0,
+                    btc_destination: String::new(),
+                    rate: 0,
+                    rate_decimals: 0,
+                    received_at: 0,
+                    txid: paid_by,
+                    refunded: None,
+                },
+            );
+        }
+
+        state.prune_settled_transactions(100 * DAY_NANOS);
+        let kept: Vec<String> = state.transactions.iter().map(|(txid, _)| txid).collect();
+        assert_eq!(kept, vec![txid(3), txid(4)]);
+        let kept: Vec<u64> = state
+            .retrieve_btc_requests
+            .iter()
+            .map(|(block_index, _)| block_index)
+            .collect();
+        assert_eq!(kept, vec![2, 3]);
+    }
+}